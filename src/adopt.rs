@@ -0,0 +1,150 @@
+// Author(s): Dylan Turner
+//! Work out what AppImages that aren't in the manifest are, so they can be adopted
+
+use crate::{
+    appimage,
+    error::{
+        Error, Result
+    }, pkg::{
+        Package, file_sha256, find_pkg
+    }, progress::note,
+    updates::version_from_file_name
+};
+
+/// Parts of AppImage file names that come after the name: architectures and the OS.
+const NAME_STOP_WORDS: [&str; 10] = [
+    "x86", "x64", "amd64", "i386", "i686", "aarch64", "arm64", "armhf", "linux", "appimage"
+];
+
+/// Work out which package an untracked AppImage is. None if it isn't an AppImage.
+///
+/// An exact copy of a repo package (by checksum) is that package. Otherwise it's matched to repo
+/// packages by the name in its metadata, then by its file name. Anything else is a local package.
+/// Fails with [`Error::NotFound`] if no name can be made for it.
+pub fn identify_untracked(file_name: &str, pkg_list: &[Package]) -> Result<Option<Package>> {
+    if appimage::detect(file_name).is_none() {
+        return Ok(None);
+    }
+    let sha256 = file_sha256(file_name)?;
+    let exact = pkg_list.iter().find(|pkg| {
+        pkg.compressed != Some(true)
+            && pkg.sha256.as_ref().is_some_and(|sum| sum.trim().to_lowercase() == sha256)
+    });
+    if let Some(pkg) = exact {
+        return Ok(Some(pkg.clone()));
+    }
+
+    let identity = appimage::identify(file_name);
+    let base_name = file_name.rsplit('/').next().unwrap_or(file_name);
+    let mut names: Vec<String> = identity.name.iter().map(|name| package_name(name)).collect();
+    names.push(name_from_file_name(base_name));
+    names.retain(|name| !name.is_empty());
+    if names.is_empty() {
+        return Err(Error::NotFound(format!("Couldn't tell the name of '{}'.", file_name)));
+    }
+    let version = identity.version.or(version_from_file_name(base_name)).unwrap_or_else(|| {
+        note(&format!("Couldn't tell the version of '{}'. Calling it 0.", file_name));
+        "0".to_string()
+    });
+
+    // The checksum is for the repo's version, which this might not be
+    Ok(Some(match names.iter().find_map(|name| find_pkg(pkg_list, name, None)) {
+        Some(pkg) => Package {
+            version,
+            sha256: None,
+            appimage_sha256: None,
+            sha1: None,
+            ..pkg.clone()
+        }, None => {
+            // adopt moves the file to its install path, so point the url there
+            let mut pkg = Package::local(
+                names.swap_remove(0), version,
+                identity.summary.unwrap_or(format!("Adopted from {}", base_name)), String::new()
+            );
+            pkg.url = format!("file://{}", pkg.install_path()?);
+            pkg
+        }
+    }))
+}
+
+/// Turn the name an AppImage gives itself into a package name, e.g. `Test App` into `test-app`.
+pub fn package_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<&str>>().join("-").to_lowercase().chars()
+        .filter(|c| c.is_alphanumeric() || "-_.+".contains(*c))
+        .collect()
+}
+
+/// Guess a package name from an AppImage's file name, e.g. `obsidian` from
+/// `Obsidian-1.4.16-x86_64.AppImage`. Everything from the version or architecture on is dropped.
+fn name_from_file_name(file_name: &str) -> String {
+    let stem = file_name.trim_end_matches(".AppImage");
+    let parts: Vec<&str> = stem.split(['-', '_']).take_while(|part| {
+        let part = part.to_lowercase();
+        !part.trim_start_matches('v').starts_with(|c: char| c.is_ascii_digit())
+            && !NAME_STOP_WORDS.contains(&part.as_str())
+    }).collect();
+    package_name(&if parts.is_empty() { stem.to_string() } else { parts.join("-") })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+    use crate::{
+        pkg::Source,
+        testing::{
+            app_home, fake_appimage, test_dir
+        }
+    };
+    use super::*;
+
+    #[test]
+    fn names_from_file_names() {
+        for (file_name, name) in [
+            ("Obsidian-1.4.16-x86_64.AppImage", "obsidian"),
+            ("Some_App_v2.0_amd64.AppImage", "some-app"),
+            ("tool-linux-x86_64.AppImage", "tool"),
+            ("123.AppImage", "123"),
+            ("-1.2.AppImage", "")
+        ] {
+            assert_eq!(name_from_file_name(file_name), name, "{}", file_name);
+        }
+        assert_eq!(package_name("Test  App+ (Beta)"), "test-app+-beta");
+    }
+
+    #[test]
+    fn identifies_untracked_appimages() {
+        let dir = test_dir("identify-untracked");
+        let appimage = fake_appimage("");
+        let file = |name: &str| {
+            let path = dir.join(name);
+            write(&path, &appimage).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        app_home();
+        let local = identify_untracked(&file("Tool-1.2-x86_64.AppImage"), &[]).unwrap().unwrap();
+        assert_eq!((local.name.as_str(), local.version.as_str()), ("tool", "1.2"));
+        assert_eq!(local.source, Some(Source::Local));
+        assert_eq!(local.url, format!("file://{}", local.install_path().unwrap()));
+
+        // A repo package with the same name is used, but with this file's version
+        let repo_pkg = Package {
+            source: None,
+            repo: Some("global".to_string()),
+            ..Package::local(
+                "tool".to_string(), "2.0".to_string(), "A tool".to_string(),
+                "https://dl.test/tool".to_string()
+            )
+        };
+        let matched = identify_untracked(&file("Tool-1.2-x86_64.AppImage"), &[ repo_pkg ])
+            .unwrap().unwrap();
+        assert_eq!((matched.version.as_str(), matched.repo.as_deref()), ("1.2", Some("global")));
+
+        let not_appimage = dir.join("notes.AppImage");
+        write(&not_appimage, "notes").unwrap();
+        assert!(identify_untracked(not_appimage.to_str().unwrap(), &[]).unwrap().is_none());
+
+        let result = identify_untracked(&file("-1.2.AppImage"), &[]);
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+}
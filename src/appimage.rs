@@ -0,0 +1,658 @@
+// Author(s): Dylan Turner
+//! Read the bits of an AppImage's ELF runtime and filesystem that aipman cares about
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{
+        BufReader, Read, Seek, SeekFrom
+    }, path::{
+        Component, Path, PathBuf
+    }
+};
+use backhand::{
+    FilesystemReader, InnerNode
+};
+use sha2::{
+    Digest, Sha256
+};
+use crate::{
+    error::{
+        Context, Error, Result
+    }, pkg::to_hex
+};
+
+/// Section holding the detached GPG signature of a signed AppImage.
+pub const SIG_SECTION: &str = ".sha256_sig";
+
+/// Section holding the public key a signed AppImage was signed with.
+pub const KEY_SECTION: &str = ".sig_key";
+
+/// Section holding the update information, e.g. `zsync|<url>`.
+pub const UPDATE_SECTION: &str = ".upd_info";
+
+/// AppImages mark themselves with `AI` and their type in the padding of the ELF identifier.
+const MAGIC_OFFSET: usize = 8;
+const MAGIC: &[u8] = b"AI";
+
+/// First bytes of a squashfs filesystem.
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+/// Where the ISO 9660 signature of a type 1 AppImage is.
+const ISO_MAGIC_OFFSET: u64 = 32769;
+const ISO_MAGIC: &[u8] = b"CD001";
+
+/// Where a type 1 AppImage keeps its update information: the application use field of the ISO's
+/// primary volume descriptor.
+const ISO_UPDATE_OFFSET: u64 = 33651;
+const ISO_UPDATE_SIZE: usize = 512;
+
+/// Directories AppStream metadata can be in, newest first.
+const APPSTREAM_DIRS: [&str; 2] = [ "/usr/share/metainfo", "/usr/share/appdata" ];
+
+/// First bytes of every PNG file.
+pub const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// How many symlinks to follow inside an AppImage before giving up.
+const MAX_LINKS: usize = 16;
+
+/// The two AppImage formats. Type 1 is an ISO 9660 image with the runtime inside it, and type 2
+/// is the runtime with a squashfs filesystem after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppImageType {
+    Type1,
+    Type2
+}
+
+/// What `aipman inspect` shows about an AppImage.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub file_name: String,
+    pub kind: AppImageType,
+
+    /// CPU architecture the runtime is built for, named like `std::env::consts::ARCH`.
+    pub arch: Option<String>,
+
+    /// Where the filesystem starts in the file.
+    pub payload_offset: u64,
+    pub update_info: Option<String>,
+    pub signed: bool,
+
+    /// Whether the key it was signed with is embedded too.
+    pub has_key: bool,
+    pub appstream: Option<AppStream>
+}
+
+impl Info {
+    pub fn print(&self) {
+        println!("AppImage:");
+        println!("| File: {}", self.file_name);
+        println!("| Type: {}", match self.kind {
+            AppImageType::Type1 => 1,
+            AppImageType::Type2 => 2
+        });
+        println!("| Architecture: {}", self.arch.clone().unwrap_or("Unknown".to_string()));
+        println!("| Payload Offset: {}", self.payload_offset);
+        println!("| Update Info: {}", self.update_info.clone().unwrap_or("None".to_string()));
+        println!("| Signed?: {}", self.signed);
+        println!("| Embedded Key?: {}", self.has_key);
+        match &self.appstream {
+            Some(appstream) => {
+                println!("| AppStream:");
+                let fields = [
+                    ("Id", &appstream.id), ("Name", &appstream.name),
+                    ("Summary", &appstream.summary), ("License", &appstream.license),
+                    ("Version", &appstream.version)
+                ];
+                for (label, value) in fields {
+                    if let Some(value) = value {
+                        println!("| - {}: {}", label, value);
+                    }
+                }
+            }, None => println!("| AppStream: None")
+        }
+    }
+}
+
+/// The parts of an AppImage's AppStream metadata worth showing.
+#[derive(Clone, Debug, Default)]
+pub struct AppStream {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub summary: Option<String>,
+    pub license: Option<String>,
+
+    /// Version of the newest release listed.
+    pub version: Option<String>
+}
+
+/// Where a section lives inside the file.
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64
+}
+
+/// The parts of an ELF file header we use.
+struct ElfHeader {
+    is_64: bool,
+    little_endian: bool,
+    machine: u64,
+    sh_off: u64,
+    sh_entsize: u64,
+    sh_num: u64,
+    sh_strndx: u64
+}
+
+impl ElfHeader {
+    /// Decode a multi-byte field with the file's endianness.
+    fn field(&self, bytes: &[u8]) -> u64 {
+        decode(bytes, self.little_endian)
+    }
+}
+
+fn decode(bytes: &[u8], little_endian: bool) -> u64 {
+    let mut value = 0;
+    for i in 0..bytes.len() {
+        let byte = if little_endian { bytes[bytes.len() - 1 - i] } else { bytes[i] };
+        value = (value << 8) | byte as u64;
+    }
+    value
+}
+
+/// Read the ELF header. Returns None if it isn't a readable ELF file.
+fn read_header(file: &mut File) -> Option<ElfHeader> {
+    let mut ident = [0; 16];
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_exact(&mut ident).ok()?;
+    if ident[0..4] != [0x7f, b'E', b'L', b'F'] {
+        return None;
+    }
+    let is_64 = match ident[4] {
+        1 => false,
+        2 => true,
+        _ => return None
+    };
+    let little_endian = match ident[5] {
+        1 => true,
+        2 => false,
+        _ => return None
+    };
+
+    // Section header table location and shape
+    let mut header = vec![0; if is_64 { 64 } else { 52 }];
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_exact(&mut header).ok()?;
+    let field = |bytes: &[u8]| decode(bytes, little_endian);
+    let machine = field(&header[18..20]);
+    let (sh_off, sh_entsize, sh_num, sh_strndx) = if is_64 {
+        (field(&header[40..48]), field(&header[58..60]), field(&header[60..62]),
+            field(&header[62..64]))
+    } else {
+        (field(&header[32..36]), field(&header[46..48]), field(&header[48..50]),
+            field(&header[50..52]))
+    };
+    Some(ElfHeader { is_64, little_endian, machine, sh_off, sh_entsize, sh_num, sh_strndx })
+}
+
+/// List the sections in an ELF file. Returns None if it isn't a readable ELF file.
+pub fn read_sections(file: &mut File) -> Option<Vec<Section>> {
+    let header = read_header(file)?;
+    let ElfHeader { is_64, sh_off, sh_entsize, sh_num, sh_strndx, .. } = header;
+    if sh_off == 0 || sh_num == 0 || sh_strndx >= sh_num
+            || sh_entsize < if is_64 { 64 } else { 40 } {
+        return None;
+    }
+    let field = |bytes: &[u8]| header.field(bytes);
+
+    // The header comes from the file itself, so don't trust it to say how much to read
+    let file_len = file.metadata().ok()?.len();
+    let table_len = sh_entsize.checked_mul(sh_num)?;
+    if sh_off.checked_add(table_len)? > file_len {
+        return None;
+    }
+
+    // Pull out (name offset, file offset, size) for every section
+    let mut table = vec![0; table_len as usize];
+    file.seek(SeekFrom::Start(sh_off)).ok()?;
+    file.read_exact(&mut table).ok()?;
+    let raw_sections: Vec<(u64, u64, u64)> = table.chunks(sh_entsize as usize).map(|entry| {
+        if is_64 {
+            (field(&entry[0..4]), field(&entry[24..32]), field(&entry[32..40]))
+        } else {
+            (field(&entry[0..4]), field(&entry[16..20]), field(&entry[20..24]))
+        }
+    }).collect();
+
+    // Then resolve their names from the string table
+    let (_, str_offset, str_size) = raw_sections[sh_strndx as usize];
+    if str_offset.checked_add(str_size)? > file_len {
+        return None;
+    }
+    let mut names = vec![0; str_size as usize];
+    file.seek(SeekFrom::Start(str_offset)).ok()?;
+    file.read_exact(&mut names).ok()?;
+    Some(raw_sections.iter().map(|(name_offset, offset, size)| {
+        let start = (*name_offset as usize).min(names.len());
+        let end = names[start..].iter().position(|byte| *byte == 0)
+            .map(|len| start + len).unwrap_or(names.len());
+        Section {
+            name: String::from_utf8_lossy(&names[start..end]).to_string(),
+            offset: *offset,
+            size: *size
+        }
+    }).collect())
+}
+
+/// Read the contents of a section, with trailing NUL padding stripped.
+///
+/// Returns None if the file isn't ELF, doesn't have the section, or the section is empty.
+pub fn read_section(file_name: &str, section_name: &str) -> Option<Vec<u8>> {
+    let mut file = File::open(file_name).ok()?;
+    let section = read_sections(&mut file)?.into_iter()
+        .find(|section| section.name == section_name)?;
+    if section.offset.checked_add(section.size)? > file.metadata().ok()?.len() {
+        return None;
+    }
+    let mut contents = vec![0; section.size as usize];
+    file.seek(SeekFrom::Start(section.offset)).ok()?;
+    file.read_exact(&mut contents).ok()?;
+    while contents.last() == Some(&0) {
+        contents.pop();
+    }
+    if contents.is_empty() {
+        None
+    } else {
+        Some(contents)
+    }
+}
+
+/// Compute the digest that an AppImage's embedded signature is made over.
+///
+/// This is the hex SHA-256 of the whole file with the signature and key sections zeroed out, which
+/// is what appimagetool signs, since those sections get filled in after the digest is taken.
+pub fn signature_digest(file_name: &str) -> Option<String> {
+    let mut file = File::open(file_name).ok()?;
+    let skipped: Vec<Section> = read_sections(&mut file)?.into_iter()
+        .filter(|section| section.name == SIG_SECTION || section.name == KEY_SECTION)
+        .collect();
+    let file_len = file.metadata().ok()?.len();
+    if skipped.iter().any(|section| section.offset.checked_add(section.size).is_none_or(
+        |end| end > file_len
+    )) {
+        return None;
+    }
+    file.seek(SeekFrom::Start(0)).ok()?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 8192];
+    let mut pos = 0;
+    loop {
+        let len = file.read(&mut buf).ok()?;
+        if len == 0 {
+            break;
+        }
+        for section in skipped.iter() {
+            let start = section.offset.max(pos);
+            let end = (section.offset + section.size).min(pos + len as u64);
+            if start < end {
+                buf[(start - pos) as usize..(end - pos) as usize].fill(0);
+            }
+        }
+        hasher.update(&buf[..len]);
+        pos += len as u64;
+    }
+    Some(to_hex(&hasher.finalize()))
+}
+
+/// Work out which kind of AppImage a file is. None if it isn't one at all, e.g. it's a web page
+/// that was saved in place of a download.
+///
+/// The magic bytes are checked first, but older AppImages don't have them, so those are recognized
+/// by the filesystem they carry instead.
+pub fn detect(file_name: &str) -> Option<AppImageType> {
+    let mut file = File::open(file_name).ok()?;
+    read_header(&mut file)?;
+    let mut ident = [0; 16];
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_exact(&mut ident).ok()?;
+    if &ident[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] == MAGIC {
+        match ident[MAGIC_OFFSET + MAGIC.len()] {
+            1 => return Some(AppImageType::Type1),
+            2 => return Some(AppImageType::Type2),
+            _ => {}
+        }
+    }
+
+    if let Some(offset) = payload_offset(&mut file) {
+        if read_at(&mut file, offset, SQUASHFS_MAGIC.len()).as_deref() == Some(SQUASHFS_MAGIC) {
+            return Some(AppImageType::Type2);
+        }
+    }
+    if read_at(&mut file, ISO_MAGIC_OFFSET, ISO_MAGIC.len()).as_deref() == Some(ISO_MAGIC) {
+        return Some(AppImageType::Type1);
+    }
+    None
+}
+
+/// The CPU architecture an ELF file is built for, named like `std::env::consts::ARCH`. None if it
+/// isn't ELF or it's an architecture we don't know.
+pub fn arch(file_name: &str) -> Option<String> {
+    let header = read_header(&mut File::open(file_name).ok()?)?;
+    let arch = match (header.machine, header.is_64) {
+        (3, _) => "x86",
+        (62, _) => "x86_64",
+        (40, _) => "arm",
+        (183, _) => "aarch64",
+        (8, false) => "mips",
+        (8, true) => "mips64",
+        (20, _) => "powerpc",
+        (21, _) => "powerpc64",
+        (243, false) => "riscv32",
+        (243, true) => "riscv64",
+        (22, _) => "s390x",
+        (258, _) => "loongarch64",
+        _ => return None
+    };
+    Some(arch.to_string())
+}
+
+/// Read everything `aipman inspect` shows about an AppImage.
+pub fn inspect(file_name: &str) -> Result<Info> {
+    let kind = detect(file_name)
+        .ok_or(Error::Parse(format!("'{}' is not an AppImage", file_name)))?;
+    let mut file = File::open(file_name).context("Failed to open AppImage")?;
+
+    // Type 1 is an ISO, so its filesystem starts at the top of the file
+    let payload_offset = match kind {
+        AppImageType::Type1 => 0,
+        AppImageType::Type2 => payload_offset(&mut file).unwrap_or(0)
+    };
+
+    Ok(Info {
+        file_name: file_name.to_string(),
+        kind,
+        arch: arch(file_name),
+        payload_offset,
+        update_info: update_info(file_name),
+        signed: read_section(file_name, SIG_SECTION).is_some(),
+        has_key: read_section(file_name, KEY_SECTION).is_some(),
+        appstream: if kind == AppImageType::Type2 { appstream(file_name) } else { None }
+    })
+}
+
+/// Read an AppImage's update information, e.g. `zsync|<url>`. None if it doesn't have any.
+pub fn update_info(file_name: &str) -> Option<String> {
+    let info = match detect(file_name)? {
+        AppImageType::Type1 => read_at(
+            &mut File::open(file_name).ok()?, ISO_UPDATE_OFFSET, ISO_UPDATE_SIZE
+        )?,
+        AppImageType::Type2 => read_section(file_name, UPDATE_SECTION)?
+    };
+    let info = String::from_utf8_lossy(&info).trim_matches(['\0', ' ']).to_string();
+    if info.is_empty() {
+        None
+    } else {
+        Some(info)
+    }
+}
+
+/// Read `len` bytes from `offset` in a file. None if the file is too short.
+fn read_at(file: &mut File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Where the filesystem of a type 2 AppImage starts, which is right after the ELF runtime. The
+/// runtime ends with its section header table.
+pub fn payload_offset(file: &mut File) -> Option<u64> {
+    let header = read_header(file)?;
+    header.sh_off.checked_add(header.sh_entsize.checked_mul(header.sh_num)?)
+}
+
+/// Open the squashfs filesystem of a type 2 AppImage.
+pub fn open_filesystem(file_name: &str) -> Option<FilesystemReader<'static>> {
+    let mut file = File::open(file_name).ok()?;
+    let offset = payload_offset(&mut file)?;
+    FilesystemReader::from_reader_with_offset(BufReader::new(file), offset).ok()
+}
+
+/// Read a file from an AppImage's filesystem, following symlinks that stay inside it.
+pub fn read_fs_file(fs: &FilesystemReader, path: &Path) -> Option<Vec<u8>> {
+    let mut path = normalize(path)?;
+    for _ in 0..MAX_LINKS {
+        let node = fs.files().find(|node| normalize(&node.fullpath).as_ref() == Some(&path))?;
+        match &node.inner {
+            InnerNode::File(file) => {
+                let mut contents = Vec::new();
+                fs.file(file).reader().read_to_end(&mut contents).ok()?;
+                return Some(contents);
+            }, InnerNode::Symlink(link) => {
+                path = normalize(&path.parent().unwrap_or(Path::new("/")).join(&link.link))?;
+            }, _ => return None
+        }
+    }
+    None
+}
+
+/// Make a path inside an AppImage absolute with no `.` or `..` in it. None if it leaves the root.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normal = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normal.push(name),
+            // Popping fails at the root
+            Component::ParentDir if !normal.pop() => return None,
+            _ => {}
+        }
+    }
+    Some(normal)
+}
+
+/// Read the AppStream metadata a type 2 AppImage ships, if it has any.
+pub fn appstream(file_name: &str) -> Option<AppStream> {
+    let fs = open_filesystem(file_name)?;
+    let path = fs.files().filter_map(|node| normalize(&node.fullpath)).find(|path| {
+        APPSTREAM_DIRS.iter().any(|dir| path.parent() == Some(Path::new(dir)))
+            && path.extension().is_some_and(|ext| ext == "xml")
+    })?;
+    let xml = String::from_utf8(read_fs_file(&fs, &path)?).ok()?;
+
+    // The first release listed is the newest
+    let version = xml.find("<release ").and_then(|start| {
+        let tag = &xml[start..start + xml[start..].find('>')?];
+        let value = &tag[tag.find("version=\"")? + "version=\"".len()..];
+        Some(value[..value.find('"')?].to_string())
+    });
+    Some(AppStream {
+        id: xml_text(&xml, "id"),
+        name: xml_text(&xml, "name"),
+        summary: xml_text(&xml, "summary"),
+        license: xml_text(&xml, "project_license"),
+        version
+    })
+}
+
+/// Get the text of the first `<tag>` in some XML. Translated copies have an `xml:lang` attribute,
+/// so they're skipped.
+fn xml_text(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    let text = xml[start..end].trim()
+        .replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+        .replace("&apos;", "'").replace("&amp;", "&");
+    Some(text)
+}
+
+/// What an AppImage says it is in its desktop entry and AppStream metadata.
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub summary: Option<String>
+}
+
+/// Find out what a type 2 AppImage says it is. The desktop entry's `Name`, `X-AppImage-Version`,
+/// and `Comment` come first, then the AppStream metadata's.
+pub fn identify(file_name: &str) -> Identity {
+    let entry = desktop_files(file_name).map(|files| files.entry).unwrap_or_default();
+    let appstream = appstream(file_name).unwrap_or_default();
+
+    // Actions have names too, so stick to the main group
+    let mut keys = HashMap::new();
+    let mut in_main = false;
+    for line in entry.lines().map(|line| line.trim()) {
+        if line.starts_with('[') {
+            in_main = line == "[Desktop Entry]";
+        } else if let Some((key, value)) = line.split_once('=').filter(|_| in_main) {
+            if !value.trim().is_empty() {
+                keys.entry(key.trim()).or_insert(value.trim().to_string());
+            }
+        }
+    }
+    Identity {
+        name: keys.remove("Name").or(appstream.name),
+        version: keys.remove("X-AppImage-Version").or(appstream.version),
+        summary: keys.remove("Comment").or(appstream.summary)
+    }
+}
+
+/// The desktop entry and icon an AppImage ships at the top of its filesystem.
+pub struct DesktopFiles {
+    pub entry: String,
+
+    /// The icon's extension (png or svg) and contents.
+    pub icon: Option<(String, Vec<u8>)>
+}
+
+/// Pull the desktop entry and icon out of a type 2 AppImage. None if it doesn't have an entry.
+pub fn desktop_files(file_name: &str) -> Option<DesktopFiles> {
+    let fs = open_filesystem(file_name)?;
+    let entry_path = fs.files().filter_map(|node| normalize(&node.fullpath)).find(|path| {
+        path.parent() == Some(Path::new("/"))
+            && path.extension().is_some_and(|ext| ext == "desktop")
+    })?;
+    let entry = String::from_utf8(read_fs_file(&fs, &entry_path)?).ok()?;
+
+    // The icon is named by the entry's Icon= key, with .DirIcon as a fallback
+    let icon_name = entry.lines().map(|line| line.trim())
+        .find_map(|line| line.strip_prefix("Icon="))
+        .map(|name| name.trim().to_string());
+    let mut icon = None;
+    if let Some(icon_name) = icon_name {
+        for ext in [ "png", "svg" ] {
+            let icon_path = format!("/{}.{}", icon_name, ext);
+            if let Some(contents) = read_fs_file(&fs, Path::new(&icon_path)) {
+                icon = Some((ext.to_string(), contents));
+                break;
+            }
+        }
+    }
+    if icon.is_none() {
+        icon = read_fs_file(&fs, Path::new("/.DirIcon")).and_then(|contents| {
+            if contents.starts_with(PNG_MAGIC) {
+                Some(("png".to_string(), contents))
+            } else if String::from_utf8_lossy(&contents).contains("<svg") {
+                Some(("svg".to_string(), contents))
+            } else {
+                None
+            }
+        });
+    }
+    Some(DesktopFiles { entry, icon })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+    use crate::testing::{
+        fake_appimage, test_dir
+    };
+    use super::*;
+
+    /// Where the `.upd_info` entry is in the section table of a fake AppImage.
+    const UPDATE_ENTRY: usize = 640 + 2 * 64;
+
+    /// Write a fake AppImage changed by `damage`, and return its path.
+    fn fake_file(name: &str, damage: impl FnOnce(&mut Vec<u8>)) -> String {
+        let mut contents = fake_appimage("zsync|https://dl.test/App.zsync");
+        damage(&mut contents);
+        let path = test_dir(&format!("appimage-{}", name)).join("App.AppImage");
+        write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn set(contents: &mut [u8], at: usize, value: u64) {
+        contents[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn reads_sections() {
+        let file = fake_file("good", |_| {});
+        assert_eq!(detect(&file), Some(AppImageType::Type2));
+        assert_eq!(update_info(&file).as_deref(), Some("zsync|https://dl.test/App.zsync"));
+        let names: Vec<String> = read_sections(&mut File::open(&file).unwrap()).unwrap()
+            .into_iter().map(|section| section.name).collect();
+        assert_eq!(names, [ "", ".shstrtab", ".upd_info" ]);
+    }
+
+    #[test]
+    fn rejects_section_table_past_end() {
+        for sh_off in [ 700, u64::MAX - 10 ] {
+            let file = fake_file("table", |contents| set(contents, 40, sh_off));
+            assert!(read_sections(&mut File::open(&file).unwrap()).is_none(), "{}", sh_off);
+            assert_eq!(update_info(&file), None);
+        }
+    }
+
+    #[test]
+    fn rejects_section_past_end() {
+        for (at, value) in [
+            (UPDATE_ENTRY + 32, 10_000), (UPDATE_ENTRY + 24, u64::MAX - 10),
+            (UPDATE_ENTRY + 32, u64::MAX)
+        ] {
+            let file = fake_file("section", |contents| set(contents, at, value));
+            assert_eq!(read_section(&file, UPDATE_SECTION), None, "{} = {}", at, value);
+        }
+
+        // The string table is read for every section's name
+        let file = fake_file("strtab", |contents| set(contents, 640 + 64 + 32, 10_000));
+        assert!(read_sections(&mut File::open(&file).unwrap()).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_string_table_index() {
+        for index in [ 3, u16::MAX ] {
+            let file = fake_file("strndx", |contents| {
+                contents[62..64].copy_from_slice(&index.to_le_bytes());
+            });
+            assert!(read_sections(&mut File::open(&file).unwrap()).is_none(), "{}", index);
+        }
+    }
+
+    #[test]
+    fn html_is_not_an_appimage() {
+        let path = test_dir("appimage-html").join("App.AppImage");
+        write(&path, "<!DOCTYPE html>\n<html><body>404 Not Found</body></html>\n").unwrap();
+        let file = path.to_str().unwrap();
+        assert_eq!(detect(file), None);
+        assert_eq!(update_info(file), None);
+    }
+
+    #[test]
+    fn reads_arch() {
+        for (machine, expected) in [
+            (0, None), (62, Some("x86_64")), (183, Some("aarch64")), (22, Some("s390x")),
+            (9999, None)
+        ] {
+            let file = fake_file("arch", |contents| {
+                contents[18..20].copy_from_slice(&(machine as u16).to_le_bytes());
+            });
+            assert_eq!(arch(&file).as_deref(), expected, "{}", machine);
+        }
+    }
+}
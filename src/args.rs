@@ -10,13 +10,21 @@ use clap::{
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// Ask before changing package information
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_ask")]
     pub ask: bool,
 
+    /// Don't ask before changing package information, even if the config says to
+    #[arg(long, overrides_with = "ask")]
+    pub no_ask: bool,
+
     /// Create a backup of ~/Applications that can be restored from
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_backup")]
     pub backup: bool,
 
+    /// Don't create a backup, even if the config says to
+    #[arg(long, overrides_with = "backup")]
+    pub no_backup: bool,
+
     /// Use a different package repo than https://github.com/blueOkiris/aip-man-pkg-list.
     /// To upgrade from that repo, run upgrade with this flag. Works with local repos via file://.
     /// Should be a link to pkgs.json like:
@@ -24,6 +32,29 @@ pub struct Args {
     #[arg(short, long)]
     pub repo: Option<String>,
 
+    /// Refuse to install AppImages that aren't signed by a key in the keyring (or the key pinned
+    /// for the package by the repo).
+    #[arg(long)]
+    pub require_signed: bool,
+
+    /// Manage packages for every user on the system in /opt/appimages instead of your own. Changing
+    /// them needs root.
+    #[arg(long)]
+    pub system: bool,
+
+    /// Put everything aipman touches (packages, manifest, config, cache, and launchers) under
+    /// this directory instead of /, e.g. to install packages into a system image being built.
+    #[arg(long)]
+    pub root: Option<String>,
+
+    /// Only use the cached package list and don't touch the network at all.
+    #[arg(long)]
+    pub offline: bool,
+
+    /// If another aipman is already changing packages, fail right away instead of waiting for it.
+    #[arg(long)]
+    pub no_wait: bool,
+
     /// One of the commands: install <pkg>, remove <pkg>, upgrade, etc.
     #[command(subcommand)]
     pub command: Commands
@@ -31,10 +62,27 @@ pub struct Args {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Installs an AppImage from the global repo.
+    /// Installs AppImages from the global repo.
     Install {
-        /// Package to install.
-        package: String
+        /// Packages to install. Paths to AppImage files can be given too.
+        #[arg(required_unless_present_any = [ "from", "url" ])]
+        packages: Vec<String>,
+
+        /// Install exactly the packages listed in a manifest (aip_man_pkg_list.json) instead.
+        #[arg(long, conflicts_with_all = [ "packages", "url" ])]
+        from: Option<String>,
+
+        /// Install the AppImage at a url instead of one from a repo.
+        #[arg(long, conflicts_with = "packages")]
+        url: Option<String>,
+
+        /// Name to install an AppImage from a url or file as, instead of the name it gives.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Version to record for an AppImage from a url or file, instead of the version it gives.
+        #[arg(long)]
+        version: Option<String>
     },
 
     /// Removes an installed AppImage.
@@ -44,7 +92,25 @@ pub enum Commands {
     },
 
     /// Upgrade installed packages.
-    Upgrade,
+    Upgrade {
+        /// How many packages to download at the same time.
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize
+    },
+
+    /// Update packages from the update information in their AppImages instead of a repo.
+    AdoptUpdates {
+        /// Installed packages to update this way.
+        #[arg(required = true)]
+        packages: Vec<String>
+    },
+
+    /// Add AppImages in ~/Applications that aipman didn't install to the manifest.
+    Adopt {
+        /// Adopt every one without asking.
+        #[arg(short, long)]
+        yes: bool
+    },
 
     /// List installed packages.
     List,
@@ -58,10 +124,104 @@ pub enum Commands {
         app_args: Option<Vec<String>>
     },
 
+    /// Show what's inside an AppImage.
+    Inspect {
+        /// Installed package or AppImage file to look at.
+        target: String
+    },
+
+    /// Check that the manifest matches what's in ~/Applications, and fix what doesn't.
+    Doctor {
+        /// Fix the problems that can be fixed without a person deciding.
+        #[arg(long)]
+        fix: bool
+    },
+
     /// Restore ~/Applications from backup.
     Restore,
 
     /// List all packages that can be installed.
-    Available
+    Available,
+
+    /// Update the cached package list.
+    Refresh,
+
+    /// Manage the keys trusted to sign AppImages.
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands
+    },
+
+    /// Manage extra command names for installed packages.
+    Alias {
+        #[command(subcommand)]
+        action: AliasCommands
+    },
+
+    /// View or change settings in ~/.config/aipman/config.toml.
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommands
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommands {
+    /// Trust a GPG public key (armored or binary).
+    Add {
+        /// File containing the key.
+        file: String
+    },
+
+    /// Stop trusting a key.
+    Remove {
+        /// Fingerprint of the key.
+        fingerprint: String
+    },
+
+    /// List trusted keys.
+    List
 }
 
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// Print the value of a setting.
+    Get {
+        /// Name of the setting.
+        key: String
+    },
+
+    /// Change a setting in your config file.
+    Set {
+        /// Name of the setting.
+        key: String,
+
+        /// New value, e.g. true, ~/Apps, or [ "https://example.com/pkgs.json" ].
+        value: String
+    },
+
+    /// Print every setting.
+    List
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AliasCommands {
+    /// Add another command that runs a package.
+    Add {
+        /// Package to run.
+        package: String,
+
+        /// Name of the command.
+        alias: String
+    },
+
+    /// Remove an alias.
+    Remove {
+        /// Name of the command.
+        alias: String
+    },
+
+    /// List aliases.
+    List
+}
@@ -0,0 +1,149 @@
+// Author(s): Dylan Turner
+//! Keep copies of repo files in ~/.cache/aipman so they're only downloaded again when they change
+
+use std::{
+    path::PathBuf,
+    fs::{
+        create_dir_all, read, read_to_string, remove_file
+    }
+};
+use dirs::cache_dir;
+use reqwest::{
+    StatusCode,
+    blocking::Client,
+    header::{
+        ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED
+    }
+};
+use serde::{
+    Serialize, Deserialize
+};
+use serde_json::{
+    from_str, to_string_pretty
+};
+use sha2::{
+    Digest, Sha256
+};
+use crate::{
+    error::{
+        Context, Error, Result
+    }, pkg::{
+        to_hex, write_atomic
+    },
+    scope::{
+        is_system, rooted, SYSTEM_CACHE_DIR
+    }
+};
+
+/// What we know about a cached file, so we can ask the server whether it changed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+
+    /// Whether the file existed on the server last time we asked.
+    exists: bool
+}
+
+/// Directory the cache lives in. Created if it doesn't exist.
+pub fn aipman_cache_dir() -> Result<PathBuf> {
+    let dir = if is_system() {
+        PathBuf::from(SYSTEM_CACHE_DIR)
+    } else {
+        let mut dir = cache_dir().ok_or(Error::missing_dir("cache"))?;
+        dir.push("aipman");
+        dir
+    };
+    let dir = rooted(dir);
+    create_dir_all(dir.clone()).context("Failed to create cache path")?;
+    Ok(dir)
+}
+
+/// Where the cache info and contents for a url are kept. Files are named by a hash of the url.
+fn cache_paths(url: &str) -> Result<(PathBuf, PathBuf)> {
+    let key = to_hex(&Sha256::digest(url.as_bytes()));
+    let dir = aipman_cache_dir()?;
+    Ok((dir.join(format!("{}.json", key)), dir.join(format!("{}.data", key))))
+}
+
+/// Get a remote file, using the cached copy if the server says it hasn't changed.
+///
+/// Returns None if the file doesn't exist on the server (a 404 or 410). When `offline` is set, only
+/// the cache is used and nothing is downloaded. If the server can't be reached or answers with any
+/// other error, the cached copy is used too, so a server having a bad moment can't make a file
+/// look deleted.
+pub fn fetch(url: &str, offline: bool) -> Result<Option<Vec<u8>>> {
+    let (entry_path, data_path) = cache_paths(url)?;
+    let cached: Option<CacheEntry> = read_to_string(&entry_path).ok()
+        .and_then(|text| from_str(&text).ok());
+    let cached_data = read(&data_path).ok();
+    let from_cache = |entry: &CacheEntry| if entry.exists { cached_data.clone() } else { None };
+
+    if offline {
+        return match &cached {
+            Some(entry) => Ok(from_cache(entry)),
+            None => Err(Error::NotFound(format!(
+                "'{}' isn't cached. Run `aipman refresh` while online first", url
+            )))
+        };
+    }
+
+    // Only ask for the file if it changed since we cached it
+    let mut request = Client::new().get(url);
+    if let (Some(entry), Some(_)) = (&cached, &cached_data) {
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(err) => return match &cached {
+            Some(entry) => {
+                println!("Warning: Failed to reach '{}' ({}). Using cached copy.", url, err);
+                Ok(from_cache(entry))
+            }, None => Err(err).context("Failed to download from repo")
+        }
+    };
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED && cached_data.is_some() {
+        return Ok(cached_data);
+    }
+    let exists = status.is_success();
+    if !exists && status != StatusCode::NOT_FOUND && status != StatusCode::GONE {
+        return match &cached {
+            Some(entry) => {
+                println!("Warning: '{}' answered {}. Using cached copy.", url, status);
+                Ok(from_cache(entry))
+            }, None => Err(Error::Network(format!(
+                "Failed to download from repo: '{}' answered {}", url, status
+            )))
+        };
+    }
+
+    let header = |name| response.headers().get(name)
+        .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+        .map(|value| value.to_string());
+    let mut entry = CacheEntry {
+        url: url.to_string(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+        exists
+    };
+    let data = if entry.exists {
+        let data = response.bytes().context("Failed to get repo file contents")?.to_vec();
+        write_atomic(&data_path, &data).context("Failed to save repo file to cache")?;
+        Some(data)
+    } else {
+        entry.etag = None;
+        entry.last_modified = None;
+        let _ = remove_file(&data_path);
+        None
+    };
+    let entry_json = to_string_pretty(&entry).context("Failed to format cache info")?;
+    write_atomic(&entry_path, entry_json.as_bytes()).context("Failed to save cache info")?;
+    Ok(data)
+}
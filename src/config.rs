@@ -0,0 +1,224 @@
+// Author(s): Dylan Turner
+//! Load aipman's settings from /etc/aipman/config.toml and ~/.config/aipman/config.toml
+
+use std::{
+    collections::BTreeMap,
+    path::{
+        Path, PathBuf
+    }, fs::{
+        create_dir_all, read_to_string, write
+    }
+};
+use dirs::{
+    config_dir, home_dir
+};
+use serde::{
+    Serialize, Deserialize
+};
+use toml::{
+    Table, Value
+};
+use crate::{
+    error::{
+        Context, Error, Result
+    }, scope::{
+        is_system, rooted, SYSTEM_CONFIG_DIR
+    }
+};
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Config shared by every user on the system. The user's own config takes precedence over it.
+pub const SYSTEM_CONFIG: &str = "/etc/aipman/config.toml";
+
+/// Settings that can be changed with `aipman config set`. Repos have to be edited in the file.
+/// Aliases are changed with `aipman alias`.
+pub const SETTINGS: [&str; 8] = [
+    "install_dir", "system_install_dir", "bin_dir", "repo", "ask", "backup", "trusted_repos",
+    "github_api"
+];
+
+/// Settings read from the config files. Anything left out of the files gets its default.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where AppImages are installed instead of ~/Applications.
+    pub install_dir: Option<String>,
+
+    /// Where system packages are installed instead of /opt/appimages.
+    pub system_install_dir: Option<String>,
+
+    /// Where launchers for user packages go instead of ~/.local/bin.
+    pub bin_dir: Option<String>,
+
+    /// Extra launcher names for packages, by package name.
+    pub aliases: BTreeMap<String, Vec<String>>,
+
+    /// Repo url to use instead of `repos`, like always passing `--repo`.
+    pub repo: Option<String>,
+
+    /// Ask before changing package information, like always passing `--ask`.
+    pub ask: bool,
+
+    /// Make a backup before every command, like always passing `--backup`.
+    pub backup: bool,
+
+    /// Repos to pull package lists from. The global package list is used if there are none.
+    pub repos: Vec<Repo>,
+
+    /// Repo urls whose package lists are used without a warning even if they aren't signed.
+    pub trusted_repos: Vec<String>,
+
+    /// GitHub API to look up releases with for self-updating packages, instead of
+    /// https://api.github.com.
+    pub github_api: Option<String>
+}
+
+/// A package list to pull packages from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Repo {
+    /// Name recorded in the manifest for packages installed from this repo.
+    pub name: String,
+    pub url: String,
+
+    /// When several repos have a package with the same name, the highest priority one wins.
+    #[serde(default)]
+    pub priority: i64,
+
+    /// Use the package list without a warning even if it isn't signed.
+    #[serde(default)]
+    pub trusted: bool,
+
+    /// Fingerprint of the key the package list has to be signed with. Only that key is checked,
+    /// and it has to be in the keyring.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+
+    /// Refuse the package list unless it's signed by a key in the keyring (or `signing_key`).
+    #[serde(default)]
+    pub require_signature: bool
+}
+
+/// Directory holding aipman's config file and keyring. System packages use /etc/aipman.
+pub fn aipman_config_dir() -> Result<PathBuf> {
+    let dir = if is_system() {
+        PathBuf::from(SYSTEM_CONFIG_DIR)
+    } else {
+        let mut dir = config_dir().ok_or(Error::missing_dir("config"))?;
+        dir.push("aipman");
+        dir
+    };
+    let dir = rooted(dir);
+    create_dir_all(dir.clone()).context("Failed to create config path")?;
+    Ok(dir)
+}
+
+/// The user's config file.
+pub fn config_path() -> Result<PathBuf> {
+    let mut file_name = aipman_config_dir()?;
+    file_name.push(CONFIG_FILE);
+    Ok(file_name)
+}
+
+/// Read the system and user config files, or use the defaults if there aren't any.
+///
+/// Each setting in the user's config replaces the same one in the system config. When managing
+/// system packages, only the system config is used.
+pub fn load_config() -> Result<Config> {
+    let mut table = read_table(&rooted(SYSTEM_CONFIG))?;
+    if !is_system() {
+        table.extend(read_table(&config_path()?)?);
+    }
+    Value::Table(table).try_into().context("Failed to parse config file")
+}
+
+/// Change a setting in the user's config file. The value is read as TOML, or as a plain string if
+/// it isn't valid TOML (so paths and urls don't need quotes).
+pub fn set_setting(key: &str, value: &str) -> Result<()> {
+    if !SETTINGS.contains(&key) {
+        return Err(Error::NotFound(format!("No such setting '{}'", key)));
+    }
+    let value = format!("value = {}", value).parse::<Table>().ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or(Value::String(value.to_string()));
+
+    let mut table = read_table(&config_path()?)?;
+    table.insert(key.to_string(), value);
+    Value::Table(table.clone()).try_into::<Config>()
+        .context(&format!("Invalid value for '{}'", key))?;
+    save_table(&table)
+}
+
+/// Add an extra launcher name for a package to the user's config file.
+pub fn add_alias(pkg_name: &str, alias: &str) -> Result<()> {
+    let mut config = load_user_config()?;
+    let aliases = config.aliases.entry(pkg_name.to_string()).or_default();
+    if !aliases.iter().any(|elem| elem == alias) {
+        aliases.push(alias.to_string());
+    }
+    save_user_config(&config)
+}
+
+/// Remove a launcher name from the user's config file, returning the package it was for.
+pub fn remove_alias(alias: &str) -> Result<String> {
+    let mut config = load_user_config()?;
+    let pkg_name = config.aliases.iter()
+        .find(|(_, aliases)| aliases.iter().any(|elem| elem == alias))
+        .map(|(pkg_name, _)| pkg_name.clone())
+        .ok_or(Error::NotFound(format!("No such alias '{}'", alias)))?;
+    let aliases = config.aliases.get_mut(&pkg_name).unwrap();
+    aliases.retain(|elem| elem != alias);
+    if aliases.is_empty() {
+        config.aliases.remove(&pkg_name);
+    }
+    save_user_config(&config)?;
+    Ok(pkg_name)
+}
+
+/// Read only the user's config file, so saving it doesn't copy in the system settings.
+fn load_user_config() -> Result<Config> {
+    Value::Table(read_table(&config_path()?)?).try_into().context("Failed to parse config file")
+}
+
+/// Save settings to the user's config file, leaving out the ones that are still the default.
+fn save_user_config(config: &Config) -> Result<()> {
+    let mut table = Table::try_from(config)
+        .map_err(|err| Error::Parse(format!("Failed to format config file: {}", err)))?;
+    let user_table = read_table(&config_path()?)?;
+    let defaults = Table::try_from(Config::default())
+        .map_err(|err| Error::Parse(format!("Failed to format config file: {}", err)))?;
+    table.retain(|key, value| user_table.contains_key(key) || defaults.get(key) != Some(value));
+    save_table(&table)
+}
+
+/// Overwrite the user's config file.
+fn save_table(table: &Table) -> Result<()> {
+    let config_text = toml::to_string(table)
+        .map_err(|err| Error::Parse(format!("Failed to format config file: {}", err)))?;
+    write(config_path()?, config_text).context("Failed to save config file")
+}
+
+/// Expand a leading `~` in a path from the config to the user's home directory.
+pub fn expand_home(path: &str) -> Result<PathBuf> {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let mut home = home_dir().ok_or(Error::missing_dir("home"))?;
+            if rest.len() > 1 {
+                home.push(&rest[1..]);
+            }
+            Ok(home)
+        }, _ => Ok(PathBuf::from(path))
+    }
+}
+
+/// Read a config file as a table, which is empty if the file doesn't exist.
+fn read_table(file_name: &Path) -> Result<Table> {
+    if !file_name.exists() {
+        return Ok(Table::new());
+    }
+
+    let config_text = read_to_string(file_name)
+        .context(&format!("Failed to read config file '{}'", file_name.display()))?;
+    config_text.parse::<Table>()
+        .context(&format!("Failed to parse config file '{}'", file_name.display()))
+}
@@ -0,0 +1,190 @@
+// Author(s): Dylan Turner
+//! Make installed AppImages show up in application menus
+
+use std::{
+    path::{
+        Path, PathBuf
+    }, fs::{
+        create_dir_all, remove_file, write
+    }, io::ErrorKind
+};
+use dirs::data_dir;
+use glob::glob;
+use crate::{
+    appimage::{
+        self, PNG_MAGIC
+    }, error::{
+        Context, Error, Result
+    }, pkg::{
+        Package, path_string
+    },
+    scope::{
+        is_system, rooted, unrooted, SYSTEM_SHARE_DIR
+    }
+};
+
+/// Icon sizes the hicolor theme has directories for.
+const ICON_SIZES: [u64; 10] = [ 16, 22, 24, 32, 48, 64, 96, 128, 256, 512 ];
+
+/// Icon types we install.
+const ICON_EXTS: [&str; 2] = [ "png", "svg" ];
+
+/// Where desktop entries and icons go: ~/.local/share, or /usr/local/share for system packages.
+fn share_dir() -> Result<PathBuf> {
+    if is_system() {
+        return Ok(rooted(SYSTEM_SHARE_DIR));
+    }
+    let dir = data_dir().ok_or(Error::missing_dir("data"))?;
+    Ok(rooted(dir))
+}
+
+/// Directory desktop entries go in.
+pub fn desktop_dir() -> Result<PathBuf> {
+    Ok(share_dir()?.join("applications"))
+}
+
+/// Where a package's desktop entry goes.
+pub fn entry_path(pkg: &Package) -> Result<PathBuf> {
+    Ok(desktop_dir()?.join(format!("aipman-{}.desktop", pkg.name)))
+}
+
+/// Name a package's icon is installed under in the icon theme.
+fn icon_name(pkg_name: &str) -> String {
+    format!("aipman-{}", pkg_name)
+}
+
+/// Add a desktop entry for a package that launches `exec`.
+///
+/// The entry and icon the AppImage ships with are used if it has them, with the entry pointed at
+/// `exec` and the installed icon. Otherwise a basic entry is made from the package info.
+pub fn install_entry(pkg: &Package, exec: &Path) -> Result<()> {
+    let exec = unrooted(exec);
+    remove_icons(&pkg.name)?;
+    let entry = match appimage::desktop_files(&pkg.install_path()?) {
+        Some(files) => {
+            let icon = match files.icon {
+                Some((ext, contents)) => {
+                    install_icon(pkg, &ext, &contents)?;
+                    Some(icon_name(&pkg.name))
+                }, None => None
+            };
+            rewrite_entry(&files.entry, pkg, &exec, icon.as_deref())
+        }, None => format!(
+            "[Desktop Entry]\nType=Application\nName={}\nComment={}\nExec=\"{}\" %U\n\
+                Terminal=false\nCategories=Utility;\nX-AppImage-Version={}\n",
+            pkg.name, pkg.description.replace('\n', " "), exec.display(), pkg.version
+        )
+    };
+
+    create_dir_all(desktop_dir()?).context("Failed to create desktop entry path")?;
+    write(entry_path(pkg)?, entry).context("Failed to write desktop entry")
+}
+
+/// Remove a package's desktop entry and icon, if it has them.
+pub fn remove_entry(pkg: &Package) -> Result<()> {
+    match remove_file(entry_path(pkg)?) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(Error::Io(format!(
+            "Failed to remove desktop entry for '{}': {}", pkg.name, err
+        ))), _ => {}
+    }
+    remove_icons(&pkg.name)
+}
+
+/// Desktop entries we made for packages that aren't installed anymore.
+pub fn stale_entries(installed: &[Package]) -> Result<Vec<PathBuf>> {
+    let pattern = desktop_dir()?.join("aipman-*.desktop");
+    let entries = glob(&path_string(&pattern)?)
+        .map_err(|err| Error::Parse(format!("Failed to search for desktop entries: {}", err)))?;
+    Ok(entries.flatten().filter(|entry| match entry_pkg_name(entry) {
+        Some(name) => !installed.iter().any(|pkg| pkg.name == name),
+        None => false
+    }).collect())
+}
+
+/// Remove a desktop entry found by [`stale_entries`] along with its icon.
+pub fn remove_stale_entry(entry: &Path) -> Result<()> {
+    match remove_file(entry) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(Error::Io(format!(
+            "Failed to remove desktop entry '{}': {}", entry.display(), err
+        ))), _ => {}
+    }
+    match entry_pkg_name(entry) {
+        Some(name) => remove_icons(name),
+        None => Ok(())
+    }
+}
+
+/// The package a desktop entry is for, going by its file name.
+fn entry_pkg_name(entry: &Path) -> Option<&str> {
+    entry.file_name()?.to_str()?.strip_prefix("aipman-")?.strip_suffix(".desktop")
+}
+
+/// Put an icon in the hicolor theme, in the directory for its size.
+fn install_icon(pkg: &Package, ext: &str, contents: &[u8]) -> Result<()> {
+    let size = if ext == "svg" {
+        "scalable".to_string()
+    } else {
+        // A PNG's width is right after its signature and the IHDR chunk header
+        let width = if contents.starts_with(PNG_MAGIC) && contents.len() >= 20 {
+            u32::from_be_bytes([ contents[16], contents[17], contents[18], contents[19] ]) as u64
+        } else {
+            0
+        };
+        let width = if ICON_SIZES.contains(&width) { width } else { 256 };
+        format!("{0}x{0}", width)
+    };
+
+    let dir = share_dir()?.join("icons/hicolor").join(size).join("apps");
+    create_dir_all(&dir).context("Failed to create icon path")?;
+    write(dir.join(format!("{}.{}", icon_name(&pkg.name), ext)), contents)
+        .context("Failed to write icon")
+}
+
+/// Remove a package's icon from every size directory it could be in.
+fn remove_icons(pkg_name: &str) -> Result<()> {
+    for ext in ICON_EXTS {
+        let pattern = share_dir()?.join("icons/hicolor/*/apps")
+            .join(format!("{}.{}", icon_name(pkg_name), ext));
+        let icons = glob(&path_string(&pattern)?)
+            .map_err(|err| Error::Parse(format!("Failed to search for icons: {}", err)))?;
+        for icon in icons.flatten() {
+            remove_file(icon).context("Failed to remove icon")?;
+        }
+    }
+    Ok(())
+}
+
+/// Point an AppImage's own desktop entry at the installed package.
+fn rewrite_entry(entry: &str, pkg: &Package, exec: &Path, icon: Option<&str>) -> String {
+    let mut lines = Vec::new();
+    let mut in_main = false;
+    for line in entry.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_main = trimmed == "[Desktop Entry]";
+            lines.push(line.to_string());
+            if in_main {
+                lines.push(format!("X-AppImage-Version={}", pkg.version));
+            }
+        } else if let Some(command) = trimmed.strip_prefix("Exec=") {
+            lines.push(format!("Exec={}", replace_program(command, exec)));
+        } else if trimmed.starts_with("TryExec=") {
+            lines.push(format!("TryExec={}", exec.display()));
+        } else if in_main && trimmed.starts_with("Icon=") && icon.is_some() {
+            lines.push(format!("Icon={}", icon.unwrap()));
+        } else if !(in_main && trimmed.starts_with("X-AppImage-Version=")) {
+            lines.push(line.to_string());
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Swap the program an Exec= line runs for `exec`, keeping its arguments.
+fn replace_program(command: &str, exec: &Path) -> String {
+    let command = command.trim_start();
+    let args = match command.strip_prefix('"') {
+        Some(quoted) => quoted.find('"').map(|end| &quoted[end + 1..]).unwrap_or(""),
+        None => command.find(char::is_whitespace).map(|end| &command[end..]).unwrap_or("")
+    };
+    format!("\"{}\"{}", exec.display(), args)
+}
@@ -0,0 +1,239 @@
+// Author(s): Dylan Turner
+//! Find places where the manifest and what's on disk don't agree, and fix the ones we can
+
+use std::{
+    collections::HashSet,
+    fmt::{
+        Display, Formatter
+    }, fs::{
+        Permissions, metadata, read_dir, remove_dir_all, remove_file, set_permissions
+    }, io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{
+        Path, PathBuf
+    }
+};
+use crate::{
+    appimage, desktop, shim,
+    error::{
+        Context, Error, Result
+    }, pkg::{
+        Package, app_dir, PERMISSION
+    }
+};
+
+/// Something wrong with the installed packages.
+#[derive(Clone, Debug)]
+pub enum Problem {
+    /// More than one manifest entry has this name.
+    Duplicate(String),
+
+    /// A package in the manifest whose AppImage is gone.
+    Missing(Package),
+
+    /// A package whose AppImage can't be run.
+    NotExecutable(Package),
+
+    /// A package whose file isn't an AppImage at all, e.g. a saved error page.
+    NotAppImage(Package),
+
+    /// An AppImage in the install directory that isn't in the manifest.
+    Untracked(PathBuf),
+
+    /// A `tmp-*` directory, or a `.part`, `.staged` or `.AppImage.old` file, left by an install
+    /// that didn't finish.
+    Leftover(PathBuf),
+
+    /// A desktop entry for a package that isn't installed.
+    StaleEntry(PathBuf),
+
+    /// A launcher pointing at an AppImage that doesn't exist.
+    StaleShim(PathBuf)
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Duplicate(name) =>
+                write!(f, "Package '{}' is in the manifest more than once.", name),
+            Problem::Missing(pkg) => write!(
+                f, "The AppImage for '{}' version '{}' is missing.", pkg.name, pkg.version
+            ), Problem::NotExecutable(pkg) =>
+                write!(f, "The AppImage for '{}' isn't executable.", pkg.name),
+            Problem::NotAppImage(pkg) =>
+                write!(f, "The file installed for '{}' isn't an AppImage.", pkg.name),
+            Problem::Untracked(path) =>
+                write!(f, "'{}' isn't in the manifest.", path.display()),
+            Problem::Leftover(path) =>
+                write!(f, "'{}' was left behind by an unfinished install.", path.display()),
+            Problem::StaleEntry(path) => write!(
+                f, "Desktop entry '{}' is for a package that isn't installed.", path.display()
+            ), Problem::StaleShim(path) => write!(
+                f, "Launcher '{}' points to an AppImage that doesn't exist.", path.display()
+            )
+        }
+    }
+}
+
+impl Problem {
+    /// What to do about a problem that needs a person to decide. None if [`Problem::fix`] can
+    /// handle it.
+    pub fn advice(&self) -> Option<String> {
+        match self {
+            Problem::NotAppImage(pkg) => Some(format!(
+                "Remove it with `aipman remove {}`, then install it again.", pkg.name
+            )), Problem::Untracked(_) => Some("Add it with `aipman adopt`.".to_string()),
+            _ => None
+        }
+    }
+
+    /// Fix the problem, updating `manifest` if that's where it is. The caller saves the manifest.
+    ///
+    /// Returns false for problems that need a person to decide. See [`Problem::advice`].
+    pub fn fix(&self, manifest: &mut Vec<Package>) -> Result<bool> {
+        match self {
+            Problem::Duplicate(name) => {
+                // Keep the last entry with its AppImage still there, since that's the newest
+                let keep = manifest.iter().rposition(|pkg| {
+                    pkg.name == *name
+                        && pkg.install_path().is_ok_and(|path| Path::new(&path).exists())
+                }).or(manifest.iter().rposition(|pkg| pkg.name == *name));
+                let mut index = 0;
+                manifest.retain(|pkg| {
+                    let retain = pkg.name != *name || Some(index) == keep;
+                    index += 1;
+                    retain
+                });
+            }, Problem::Missing(missing) => {
+                manifest.retain(|pkg| pkg.name != missing.name || pkg.version != missing.version);
+
+                // A duplicate entry might still be using the launchers
+                if !manifest.iter().any(|pkg| pkg.name == missing.name) {
+                    missing.remove_launchers();
+                }
+            }, Problem::NotExecutable(pkg) => set_permissions(
+                pkg.install_path()?, Permissions::from_mode(PERMISSION)
+            ).context("Failed to set package permissions")?,
+            Problem::NotAppImage(_) | Problem::Untracked(_) => return Ok(false),
+            Problem::Leftover(path) | Problem::StaleShim(path) => remove_path(path)?,
+            Problem::StaleEntry(path) => desktop::remove_stale_entry(path)?
+        }
+        Ok(true)
+    }
+}
+
+/// Look for everything that's wrong with the installed packages in `manifest`.
+pub fn check(manifest: &[Package]) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    for pkg in manifest {
+        if !seen.insert(&pkg.name) && !duplicates.contains(&pkg.name) {
+            duplicates.push(pkg.name.clone());
+        }
+    }
+    problems.extend(duplicates.into_iter().map(Problem::Duplicate));
+
+    // Duplicate entries share a file, so only look at each one once
+    let mut checked = HashSet::new();
+    for pkg in manifest.iter().filter(|pkg| checked.insert((&pkg.name, &pkg.version))) {
+        let path = pkg.install_path()?;
+        match metadata(&path) {
+            Ok(meta) => {
+                if meta.permissions().mode() & 0o111 == 0 {
+                    problems.push(Problem::NotExecutable(pkg.clone()));
+                }
+                if appimage::detect(&path).is_none() {
+                    problems.push(Problem::NotAppImage(pkg.clone()));
+                }
+            }, Err(_) => problems.push(Problem::Missing(pkg.clone()))
+        }
+    }
+
+    let tracked = manifest.iter().map(|pkg| pkg.install_path()).collect::<Result<Vec<String>>>()?;
+    let mut files: Vec<PathBuf> = read_dir(app_dir()?).context("Failed to read ~/Applications")?
+        .flatten().map(|entry| entry.path()).collect();
+    files.sort();
+    for file in files {
+        let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+        let leftover = [ ".part", ".staged", ".AppImage.old" ].iter()
+            .any(|ext| file_name.ends_with(ext));
+        if file.is_dir() && file_name.starts_with("tmp-") || file.is_file() && leftover {
+            problems.push(Problem::Leftover(file));
+        } else if file.is_file() && file_name.ends_with(".AppImage")
+            && !tracked.iter().any(|tracked| Path::new(tracked) == file) {
+            problems.push(Problem::Untracked(file));
+        }
+    }
+
+    problems.extend(desktop::stale_entries(manifest)?.into_iter().map(Problem::StaleEntry));
+    problems.extend(shim::stale_shims()?.into_iter().map(Problem::StaleShim));
+    Ok(problems)
+}
+
+/// Delete a file, link, or directory. It already being gone isn't an error.
+fn remove_path(path: &Path) -> Result<()> {
+    let result = if path.is_dir() && !path.is_symlink() {
+        remove_dir_all(path)
+    } else {
+        remove_file(path)
+    };
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(Error::Io(format!(
+            "Failed to remove '{}': {}", path.display(), err
+        ))), _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{
+        Permissions, set_permissions, write
+    };
+    use crate::testing::{
+        app_home, fake_appimage
+    };
+    use super::*;
+
+    #[test]
+    fn finds_leftovers() {
+        let home = app_home();
+        let names = [
+            "doctor-left-1.0.AppImage.part", "doctor-left-1.0.AppImage.staged",
+            "doctor-left-1.0.AppImage.old"
+        ];
+        for name in names {
+            write(home.join(name), "").unwrap();
+        }
+        let problems = check(&[]).unwrap();
+        for name in names {
+            assert!(problems.iter().any(|problem| matches!(
+                problem, Problem::Leftover(path) if *path == home.join(name)
+            )), "{} in {:?}", name, problems);
+        }
+    }
+
+    #[test]
+    fn checks_duplicates_once() {
+        app_home();
+        let pkg = |name: &str| Package::local(
+            name.to_string(), "1.0".to_string(), String::new(), String::new()
+        );
+        let (gone, stuck) = (pkg("doctor-gone"), pkg("doctor-stuck"));
+        let stuck_path = stuck.install_path().unwrap();
+        write(&stuck_path, fake_appimage("")).unwrap();
+        set_permissions(&stuck_path, Permissions::from_mode(0o644)).unwrap();
+
+        let manifest = [ gone.clone(), stuck.clone(), gone, stuck ];
+        let problems = check(&manifest).unwrap();
+        let count = |name: &str| problems.iter().filter(|problem| match problem {
+            Problem::Duplicate(dup) => dup == name,
+            Problem::Missing(pkg) | Problem::NotExecutable(pkg) | Problem::NotAppImage(pkg) =>
+                pkg.name == name,
+            _ => false
+        }).count();
+        assert_eq!(count("doctor-gone"), 2, "{:?}", problems);
+        assert_eq!(count("doctor-stuck"), 2, "{:?}", problems);
+    }
+}
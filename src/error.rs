@@ -0,0 +1,84 @@
+// Author(s): Dylan Turner
+//! The error type returned by everything in aipman that can fail
+
+use std::fmt::{
+    Display, Formatter
+};
+
+/// Everything that can go wrong while managing packages.
+///
+/// Each variant carries a message saying what we were trying to do and why it failed.
+#[derive(Debug)]
+pub enum Error {
+    /// Couldn't reach a repo or download a package.
+    Network(String),
+
+    /// A package list, manifest, config file, key, or signature couldn't be understood.
+    Parse(String),
+
+    /// Reading or writing something on disk failed.
+    Io(String),
+
+    /// A download didn't match its checksum or signature.
+    Integrity(String),
+
+    /// A package, file, or key that was asked for doesn't exist.
+    NotFound(String),
+
+    /// Another aipman process is holding the lock.
+    Locked(String)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Network(msg) | Error::Parse(msg) | Error::Io(msg)
+                | Error::Integrity(msg) | Error::NotFound(msg)
+                | Error::Locked(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The OS didn't tell us where one of the standard directories (home, config, ...) is.
+    pub fn missing_dir(kind: &str) -> Self {
+        Error::NotFound(format!(
+            "Um. Somehow you don't have a {} directory. You can't use this tool", kind
+        ))
+    }
+}
+
+/// Attach a message to a lower level error, sorting it into the right kind of Error.
+///
+/// This is the Result equivalent of `.expect("Failed to do the thing")`.
+pub trait Context<T> {
+    fn context(self, msg: &str) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, std::io::Error> {
+    fn context(self, msg: &str) -> Result<T> {
+        self.map_err(|err| Error::Io(format!("{}: {}", msg, err)))
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, reqwest::Error> {
+    fn context(self, msg: &str) -> Result<T> {
+        self.map_err(|err| Error::Network(format!("{}: {}", msg, err)))
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, serde_json::Error> {
+    fn context(self, msg: &str) -> Result<T> {
+        self.map_err(|err| Error::Parse(format!("{}: {}", msg, err)))
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, toml::de::Error> {
+    fn context(self, msg: &str) -> Result<T> {
+        self.map_err(|err| Error::Parse(format!("{}: {}", msg, err)))
+    }
+}
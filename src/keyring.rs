@@ -0,0 +1,134 @@
+// Author(s): Dylan Turner
+//! Keep track of the GPG public keys aipman trusts and check signatures against them
+
+use std::{
+    path::PathBuf,
+    fs::{
+        create_dir_all, read, read_dir, remove_file, write
+    }
+};
+use pgp::{
+    composed::{
+        Deserializable, DetachedSignature, SignedPublicKey
+    }, types::{
+        KeyDetails, VerifyingKey
+    }
+};
+use crate::{
+    config::aipman_config_dir,
+    error::{
+        Context, Error, Result
+    }
+};
+
+/// What checking a signature against a set of keys turned up.
+pub enum Verification {
+    /// The signature is good and was made by the key with this fingerprint.
+    Valid(String),
+
+    /// The signature claims to be from the key with this fingerprint, but doesn't match the data.
+    Invalid(String),
+
+    /// None of the keys we were given made the signature.
+    UnknownKey,
+
+    /// The signature couldn't be parsed at all.
+    Malformed
+}
+
+/// Directory holding trusted keys, one file per key, named by fingerprint.
+pub fn keyring_dir() -> Result<PathBuf> {
+    let mut dir = aipman_config_dir()?;
+    dir.push("keyring");
+    create_dir_all(dir.clone()).context("Failed to create keyring path")?;
+    Ok(dir)
+}
+
+/// Parse an armored or binary public key.
+pub fn parse_key(bytes: &[u8]) -> Option<SignedPublicKey> {
+    SignedPublicKey::from_reader_single(bytes).ok().map(|(key, _)| key)
+}
+
+/// Fingerprint of a key in the uppercase hex form gpg shows.
+pub fn fingerprint(key: &SignedPublicKey) -> String {
+    format!("{:X}", key.fingerprint())
+}
+
+/// Normalize a user or repo provided fingerprint so it can be compared to ours.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()
+}
+
+/// Load every key in the keyring. Files that don't parse are skipped with a warning.
+pub fn load_keys() -> Result<Vec<SignedPublicKey>> {
+    let mut keys = Vec::new();
+    let entries = read_dir(keyring_dir()?).context("Failed to read keyring")?;
+    for entry in entries.flatten() {
+        let bytes = read(entry.path()).context("Failed to read key from keyring")?;
+        match parse_key(&bytes) {
+            Some(key) => keys.push(key),
+            None => println!(
+                "Warning: Failed to parse key '{}' in keyring. Skipping.",
+                entry.path().display()
+            )
+        }
+    }
+    Ok(keys)
+}
+
+/// Add a key to the keyring, returning its fingerprint.
+pub fn import_key(bytes: &[u8]) -> Result<String> {
+    let key = parse_key(bytes)
+        .ok_or(Error::Parse("Data does not contain a valid GPG public key".to_string()))?;
+    let fpr = fingerprint(&key);
+    let mut file_name = keyring_dir()?;
+    file_name.push(format!("{}.key", fpr));
+    write(file_name, bytes).context("Failed to save key to keyring")?;
+    Ok(fpr)
+}
+
+/// Remove a key from the keyring.
+pub fn remove_key(fpr: &str) -> Result<()> {
+    let mut file_name = keyring_dir()?;
+    file_name.push(format!("{}.key", normalize_fingerprint(fpr)));
+    if !file_name.exists() {
+        return Err(Error::NotFound(format!("No such key '{}' in keyring!", fpr)));
+    }
+    remove_file(file_name).context("Failed to remove key from keyring")
+}
+
+/// Check a detached signature over some data against a set of keys (and their subkeys).
+pub fn verify(signature: &[u8], data: &[u8], keys: &[SignedPublicKey]) -> Verification {
+    let signature = match DetachedSignature::from_reader_single(signature) {
+        Ok((signature, _)) => signature,
+        Err(_) => return Verification::Malformed
+    };
+    for key in keys {
+        let fpr = fingerprint(key);
+        if signed_by(&signature, key) {
+            return check(&signature, key, data, fpr);
+        }
+        for subkey in key.public_subkeys.iter() {
+            if signed_by(&signature, subkey) {
+                return check(&signature, subkey, data, fpr);
+            }
+        }
+    }
+    Verification::UnknownKey
+}
+
+/// Does the signature say it was made by this key?
+fn signed_by(signature: &DetachedSignature, key: &impl VerifyingKey) -> bool {
+    signature.signature.issuer_fingerprint().iter().any(|fpr| **fpr == key.fingerprint())
+        || signature.signature.issuer_key_id().iter().any(|id| **id == key.legacy_key_id())
+}
+
+fn check(
+        signature: &DetachedSignature, key: &impl VerifyingKey,
+        data: &[u8], fpr: String) -> Verification {
+    if signature.verify(key, data).is_ok() {
+        Verification::Valid(fpr)
+    } else {
+        Verification::Invalid(fpr)
+    }
+}
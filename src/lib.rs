@@ -0,0 +1,29 @@
+// Author(s): Dylan Turner
+//! The App Image Package Manager as a library, so other tools can install and manage AppImages
+//! without going through the aipman command line.
+//!
+//! Nothing in here panics on a failed download or a bad file. Everything returns an
+//! [`error::Result`] instead, so callers get to decide what to do about it.
+
+pub mod adopt;
+pub mod appimage;
+pub mod cache;
+pub mod config;
+pub mod desktop;
+pub mod doctor;
+pub mod error;
+pub mod keyring;
+pub mod lock;
+pub mod pkg;
+pub mod progress;
+pub mod scope;
+pub mod shim;
+pub mod updates;
+pub mod zsync;
+
+#[cfg(test)]
+mod testing;
+
+pub use error::{
+    Error, Result
+};
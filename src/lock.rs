@@ -0,0 +1,45 @@
+// Author(s): Dylan Turner
+//! Keep more than one aipman from changing packages at the same time
+
+use std::fs::{
+    File, TryLockError
+};
+use crate::{
+    error::{
+        Context, Error, Result
+    }, pkg::aipman_data_dir
+};
+
+/// Lock file next to the manifest that every command that changes packages holds while it runs.
+///
+/// It lives with the manifest rather than in the install directory, since that can be changed from
+/// run to run while the manifest stays put.
+pub const LOCK_FILE: &str = ".aipman.lock";
+
+/// An exclusive hold on the manifest and the packages in it. The lock is released when this is
+/// dropped.
+pub struct Lock {
+    _file: File
+}
+
+/// Take the lock, either waiting for whoever has it to finish, or failing right away.
+pub fn lock(wait: bool) -> Result<Lock> {
+    let file_name = aipman_data_dir()?.join(LOCK_FILE);
+    let file = File::options().create(true).truncate(false).write(true).open(file_name)
+        .context("Failed to open lock file")?;
+    match file.try_lock() {
+        Ok(_) => {},
+        Err(TryLockError::WouldBlock) => {
+            if !wait {
+                return Err(Error::Locked(
+                    "Another aipman process is changing packages. Try again later".to_string()
+                ));
+            }
+            println!("Waiting for another aipman process to finish...");
+            file.lock().context("Failed to lock manifest")?;
+        }, Err(TryLockError::Error(err)) => {
+            return Err(err).context("Failed to lock manifest");
+        }
+    }
+    Ok(Lock { _file: file })
+}
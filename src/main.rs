@@ -2,18 +2,18 @@
 //! Entry point for the App Image Package Manager and core functions
 
 mod args;
-mod pkg;
 
 use std::{
-    path::Path,
-    fs::{
-        File, create_dir_all, remove_dir_all
+    path::{
+        Component, Path, PathBuf, absolute
+    }, fs::{
+        File, Permissions, create_dir_all, remove_dir_all, remove_file, read, read_dir, rename
     }, io::{
         stdin, stdout, Write
-    }
+    }, os::unix::fs::PermissionsExt,
+    process::exit
 };
 use clap::Parser;
-use dirs::home_dir;
 use flate2::{
     Compression,
     write::GzEncoder,
@@ -22,70 +22,294 @@ use flate2::{
 use tar::{
     Builder, Archive
 };
-use crate::{
+use aipman::{
+    Error, Result, appimage, doctor,
+    adopt::{
+        identify_untracked, package_name
+    }, error::Context,
+    config::{
+        self, load_config, set_setting, SETTINGS
+    }, lock::lock,
     pkg::{
-        pull_package_list, get_pkg_manifest, update_pkg_manifest,
-        APP_DIR
-    }, args::{
-        Args, Commands
-    }
+        Package, repos, pull_package_list, pull_package_lists, find_pkg, get_pkg_manifest,
+        update_pkg_manifest, install_pkg, download_all, download_file, commit_staged, app_dir,
+        aipman_data_dir, backup_path, manifest_path, migrate_old_layout, read_manifest, path_string,
+        Source, MANIFEST_FILE, PERMISSION, URL_DOWNLOAD_FILE
+    }, scope::{
+        is_root, is_system, rooted, set_root, set_system, SYSTEM_DATA_DIR
+    }, keyring::{
+        load_keys, import_key, remove_key, fingerprint
+    }, shim::{
+        install_shim, remove_command
+    }, updates::is_supported
+};
+use crate::args::{
+    AliasCommands, Args, Commands, ConfigCommands, KeyCommands
 };
 
 fn main() {
     let args = Args::parse();
-    if args.backup {
-        create_backup();
+    if let Err(err) = run_command(args) {
+        eprintln!("Error: {}", err);
+        exit(1);
+    }
+}
+
+/// Run whichever subcommand was asked for
+fn run_command(args: Args) -> Result<()> {
+    set_system(args.system);
+    if let Some(root) = &args.root {
+        set_root(absolute(root).context("Failed to find alternate root")?);
+    }
+
+    // Flags take precedence over the config
+    let config = load_config()?;
+    let ask = (args.ask || config.ask) && !args.no_ask;
+    let backup = (args.backup || config.backup) && !args.no_backup;
+    let repo = args.repo.or(config.repo);
+
+    let changes_pkgs = matches!(
+        args.command,
+        Commands::Install { .. } | Commands::Remove { .. } | Commands::Upgrade { .. }
+            | Commands::AdoptUpdates { .. } | Commands::Adopt { .. } | Commands::Restore
+            | Commands::Doctor { fix: true }
+    ) || backup;
+    if is_system() && args.root.is_none() && changes_pkgs && !is_root() {
+        return Err(Error::Io(
+            "Changing system packages needs root. Try again with sudo".to_string()
+        ));
+    }
+
+    // Anything that changes packages needs the manifest to itself
+    let _lock = if changes_pkgs { Some(lock(!args.no_wait)?) } else { None };
+    migrate_old_layout()?;
+
+    if args.offline && downloads(&args.command) {
+        return Err(Error::Network(
+            "Can't download packages while offline. Only AppImage files can be installed"
+                .to_string()
+        ));
+    }
+
+    if backup {
+        create_backup()?;
     }
     match args.command {
-        Commands::Install { package } => install_package(&package, args.ask, &args.repo),
-        Commands::Remove { package } => remove_package(&package, args.ask),
-        Commands::Upgrade => upgrade_packages(args.ask, &args.repo),
+        Commands::Install { packages, from, url, name, version } => match (from, url) {
+            (Some(from), _) => install_from_manifest(&from, ask, args.require_signed),
+            (None, Some(url)) => install_local(
+                &mut get_pkg_manifest()?, &url, &name, &version, ask, args.require_signed
+            ), (None, None) => install_packages(
+                &packages, &name, &version, ask, &repo, args.require_signed
+            )
+        }, Commands::Remove { package } => remove_package(&package, ask),
+        Commands::Upgrade { jobs } => upgrade_packages(
+            ask, &repo, args.require_signed, jobs
+        ),
+        Commands::AdoptUpdates { packages } => adopt_updates(&packages, ask),
+        Commands::Adopt { yes } => adopt(yes, &repo, args.offline),
         Commands::List => list_packages(),
         Commands::Run { app, app_args } => run_app(
-            &app, &app_args.unwrap_or(Vec::new()), args.ask
-        ), Commands::Restore => restore(args.ask),
-        Commands::Available => list_available(&args.repo)
+            &app, &app_args.unwrap_or(Vec::new()), ask
+        ), Commands::Inspect { target } => inspect(&target),
+        Commands::Doctor { fix } => doctor(fix, ask),
+        Commands::Restore => restore(ask),
+        Commands::Available => list_available(&repo, args.offline),
+        Commands::Refresh => refresh(&repo, args.offline),
+        Commands::Key { action } => match action {
+            KeyCommands::Add { file } => add_key(&file),
+            KeyCommands::Remove { fingerprint } => forget_key(&fingerprint),
+            KeyCommands::List => list_keys()
+        }, Commands::Alias { action } => match action {
+            AliasCommands::Add { package, alias } => add_alias(&package, &alias),
+            AliasCommands::Remove { alias } => remove_alias(&alias),
+            AliasCommands::List => list_aliases()
+        }, Commands::Config { action } => match action {
+            ConfigCommands::Get { key } => get_setting(&key),
+            ConfigCommands::Set { key, value } => set_setting(&key, &value),
+            ConfigCommands::List => list_settings()
+        }
+    }
+}
+
+/// Does the command have to download packages? Installing AppImage files doesn't.
+fn downloads(command: &Commands) -> bool {
+    match command {
+        Commands::Install { packages, from, url, .. } => from.is_some() || match url {
+            Some(url) => !url.starts_with("file://"),
+            None => !packages.iter().all(|pkg_name| is_file(pkg_name))
+        }, Commands::Upgrade { .. } => true,
+        _ => false
     }
 }
 
-/// Create a backup of ~/Applications as a tar that can be used for restorations
-fn create_backup() {
+/// Create a backup of ~/Applications and the manifest as a tar that can be used for restorations
+///
+/// Installed files go under `apps/` in the archive, and the manifest goes under `data/`.
+fn create_backup() -> Result<()> {
     println!("Creating backup. This may take a while.");
 
-    // First, create the /home/AppImages directory if it doesn't exist
-    let mut app_dir = home_dir()
-        .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-    app_dir.push(APP_DIR);
-    create_dir_all(app_dir.clone()).expect("Failed to create Application path");
-
-    let home = home_dir()
-        .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-    let home = home.to_str().unwrap();
-    let tar_gz = File::create(format!("{}/.aipman_backup.tar.gz", home))
-        .expect("Failed to create backup archive.");
+    let app_dir = app_dir()?;
+    let tar_gz = File::create(backup_path()?).context("Failed to create backup archive")?;
     let enc = GzEncoder::new(tar_gz, Compression::default());
     let mut tar = Builder::new(enc);
-    tar.append_dir_all("Applications", app_dir).expect("Failed to make backup of ~/Applications");
+    tar.append_dir("apps", &app_dir).context("Failed to make backup of ~/Applications")?;
+    for entry in read_dir(&app_dir).context("Failed to read ~/Applications")?.flatten() {
+        let name = Path::new("apps").join(entry.file_name());
+        if entry.path().is_dir() {
+            tar.append_dir_all(name, entry.path())
+        } else {
+            tar.append_path_with_name(entry.path(), name)
+        }.context("Failed to make backup of ~/Applications")?;
+    }
+
+    let manifest = manifest_path()?;
+    tar.append_dir("data", aipman_data_dir()?).context("Failed to make backup of manifest")?;
+    for file_name in [ manifest.clone(), format!("{}.bak", manifest) ] {
+        let file_name = Path::new(&file_name);
+        if file_name.exists() {
+            let name = Path::new("data").join(file_name.file_name().unwrap_or_default());
+            tar.append_path_with_name(file_name, name)
+                .context("Failed to make backup of manifest")?;
+        }
+    }
+    tar.finish().context("Failed to finish backup archive")?;
 
     println!("Done.");
+    Ok(())
 }
 
-/// Attempt to install a package or upgrade to a newer version.
-fn install_package(pkg_name: &str, ask: bool, repo: &Option<String>) {
-    let pkg_list = pull_package_list(repo);
+/// Install several packages, pulling the package list once.
+///
+/// Paths to AppImage files are installed as local packages, with `name` and `version` if given.
+fn install_packages(
+        pkg_names: &[String], name: &Option<String>, version: &Option<String>, ask: bool,
+        repo: &Option<String>, require_signed: bool) -> Result<()> {
+    if (name.is_some() || version.is_some()) && !(pkg_names.len() == 1 && is_file(&pkg_names[0])) {
+        return Err(Error::Parse(
+            "--name and --version can only be used to install a single url or file".to_string()
+        ));
+    }
+
+    let mut pkg_list = None;
+    let mut pkg_manifest = get_pkg_manifest()?;
+    for pkg_name in pkg_names {
+        if is_file(pkg_name) {
+            if !Path::new(pkg_name).is_file() {
+                return Err(Error::NotFound(format!("No such file '{}'", pkg_name)));
+            }
+            let path = absolute(pkg_name).context("Failed to find AppImage")?;
+            let url = format!("file://{}", path.display());
+            install_local(&mut pkg_manifest, &url, name, version, ask, require_signed)?;
+            continue;
+        }
+        if pkg_list.is_none() {
+            pkg_list = Some(pull_package_lists(&repos(repo)?, false)?);
+        }
+        install_package(
+            pkg_list.as_ref().unwrap(), &mut pkg_manifest, pkg_name, ask, repo, require_signed
+        )?;
+    }
+    Ok(())
+}
 
-    if !pkg_list.iter().any(|pkg| pkg.name == pkg_name) {
-        println!("Could not find package by the name of '{}'.", pkg_name);
-        return;
+/// Is an argument to install a file rather than the name of a package?
+fn is_file(pkg_name: &str) -> bool {
+    pkg_name.contains('/') || pkg_name.ends_with(".AppImage")
+}
+
+/// Install an AppImage from a url or file that isn't in a repo, recording it as a local package
+///
+/// Unless they're given, its name and version come from its desktop entry or AppStream metadata,
+/// so it's downloaded to a temporary file first to look at them. Files are looked at in place.
+fn install_local(
+        pkg_manifest: &mut Vec<Package>, url: &str, name: &Option<String>,
+        version: &Option<String>, ask: bool, require_signed: bool) -> Result<()> {
+    let tmp_path = format!("{}/{}", app_dir()?.display(), URL_DOWNLOAD_FILE);
+    let file_name = match url.strip_prefix("file://") {
+        Some(path) => path.to_string(),
+        None => {
+            println!("Downloading '{}'...", url);
+            download_file(url, &tmp_path, url.rsplit('/').next().unwrap_or(url))?;
+            tmp_path.clone()
+        }
+    };
+    let result = install_local_file(
+        pkg_manifest, url, &file_name, name, version, ask, require_signed
+    );
+    let _ = remove_file(&tmp_path);
+    result
+}
+
+/// Does the work of install_local once the AppImage is on disk at `file_name`.
+fn install_local_file(
+        pkg_manifest: &mut Vec<Package>, url: &str, file_name: &str, name: &Option<String>,
+        version: &Option<String>, ask: bool, require_signed: bool) -> Result<()> {
+    if appimage::detect(file_name).is_none() {
+        return Err(Error::Integrity(format!("'{}' is not an AppImage", url)));
+    }
+    let identity = appimage::identify(file_name);
+    let name = name.clone()
+        .or(identity.name.map(|name| package_name(&name)).filter(|name| !name.is_empty()))
+        .ok_or(Error::NotFound(format!(
+            "Couldn't tell the name of '{}'. Give it one with --name", url
+        )))?;
+    let version = version.clone().or(identity.version).ok_or(Error::NotFound(format!(
+        "Couldn't tell the version of '{}'. Give it one with --version", url
+    )))?;
+    let pkg = Package::local(
+        name, version, identity.summary.unwrap_or(format!("Installed from {}", url)),
+        url.to_string()
+    );
+    pkg.print();
+
+    let installed = pkg_manifest.iter().find(|elem| elem.name == pkg.name).cloned();
+    if let Some(installed) = &installed {
+        if installed.version == pkg.version {
+            println!("Package '{}' version '{}' is already installed.", pkg.name, pkg.version);
+            return Ok(());
+        }
+        println!(
+            "Package '{}' version '{}' is already installed. It will be replaced.",
+            pkg.name, installed.version
+        );
     }
+    if !prompt("Do you want to install the package?", ask) {
+        return Ok(());
+    }
+
+    // It's already here, so stage it from the file, but remember where it really came from
+    let local = Package { url: format!("file://{}", file_name), ..pkg.clone() };
+    local.download(require_signed, None)?;
+    commit_staged(pkg_manifest, &pkg, installed.as_ref())
+}
 
-    let pkg = pkg_list.iter().find(|elem| elem.name == pkg_name).unwrap().clone();
+/// Attempt to install a package or upgrade to a newer version.
+///
+/// If the package is already installed, it's looked up in the repo it was installed from, unless a
+/// repo was given on the command line.
+fn install_package(
+        pkg_list: &[Package], pkg_manifest: &mut Vec<Package>, pkg_name: &str, ask: bool,
+        repo: &Option<String>, require_signed: bool) -> Result<()> {
+    let installed = pkg_manifest.iter().find(|elem| elem.name == pkg_name).cloned();
+    let pinned = installed.as_ref().and_then(|pkg| pkg.repo.clone()).filter(|_| repo.is_none());
+
+    let pkg = match find_pkg(pkg_list, pkg_name, pinned.as_deref()) {
+        Some(pkg) => pkg.clone(),
+        None => {
+            match pinned {
+                Some(pinned) => println!(
+                    "Could not find package by the name of '{}' in repo '{}'.", pkg_name, pinned
+                ), None => println!("Could not find package by the name of '{}'.", pkg_name)
+            }
+            return Ok(());
+        }
+    };
     pkg.print();
 
     // Check for if installed
-    let mut pkg_manifest = get_pkg_manifest();
-    if pkg_manifest.iter().any(|installed_pkg| installed_pkg.name == pkg.name) {
-        let installed = pkg_manifest.iter().find(|elem| elem.name == pkg.name).unwrap().clone();
+    let mut replaced = None;
+    if let Some(installed) = installed {
         if installed.upgradable_to(&pkg) {
             println!(
                 "Package '{}' is already installed. However there is an upgrade available.",
@@ -93,126 +317,371 @@ fn install_package(pkg_name: &str, ask: bool, repo: &Option<String>) {
             );
 
             if !prompt("Do you want to upgrade the package?", ask) {
-                return;
+                return Ok(());
             }
 
             println!("The current version will be removed.");
-
-            // Remove from manifest
-            for i in 0..pkg_manifest.len() {
-                if pkg_manifest[i].name == pkg.name {
-                    pkg_manifest.remove(i);
-                    break;
-                }
-            }
-
-            // Delete the file
-            installed.remove();
+            replaced = Some(installed);
         } else {
             println!("Package '{}' version '{}' is already installed.", pkg.name, pkg.version);
-            return;
+            return Ok(());
         }
     }
 
     if !prompt("Package found.\nDo you want to install the package?", ask) {
-        return;
+        return Ok(());
     }
 
     println!("Downloading...");
-    pkg.download();
+    install_pkg(pkg_manifest, &pkg, replaced.as_ref(), require_signed)
+}
 
-    // Update manifest
-    pkg_manifest.push(pkg.clone());
-    update_pkg_manifest(&pkg_manifest);
+/// Install exactly the packages in a manifest, e.g. one from another machine, like a lockfile.
+///
+/// The versions, urls, and checksums in it are used as they are, without pulling any package list.
+fn install_from_manifest(file_name: &str, ask: bool, require_signed: bool) -> Result<()> {
+    let locked = read_manifest(file_name)?;
+    let mut pkg_manifest = get_pkg_manifest()?;
+    for pkg in locked {
+        let installed = pkg_manifest.iter().find(|elem| elem.name == pkg.name).cloned();
+        if installed.as_ref().is_some_and(|installed| installed.version == pkg.version) {
+            println!("Package '{}' version '{}' is already installed.", pkg.name, pkg.version);
+            continue;
+        }
+
+        if !prompt(&format!("Install '{}' version '{}'?", pkg.name, pkg.version), ask) {
+            continue;
+        }
+        println!("Downloading '{}'...", pkg.name);
+        install_pkg(&mut pkg_manifest, &pkg, installed.as_ref(), require_signed)?;
+    }
+    Ok(())
 }
 
 /// Remove a package
-fn remove_package(pkg_name: &str, ask: bool) {
-    let mut manifest = get_pkg_manifest();
+fn remove_package(pkg_name: &str, ask: bool) -> Result<()> {
+    let mut manifest = get_pkg_manifest()?;
     if !manifest.iter().any(|pkg| pkg.name == pkg_name) {
         println!("No such package '{}' installed!", pkg_name);
-        return
+        return Ok(());
     }
     
     if !prompt("Package found.\nAre you sure you want to remove the package?", ask) {
-        return;
+        return Ok(());
     }
 
     println!("Removing '{}'", pkg_name);
     
-    let pkg = manifest.iter().find(|pkg| pkg.name == pkg_name).unwrap();
-    pkg.remove();
+    let pkg = manifest.iter().find(|pkg| pkg.name == pkg_name).unwrap().clone();
+    pkg.remove()?;
+    pkg.remove_launchers();
     
-    for i in 0..manifest.len() {
-        if manifest[i].name == pkg.name {
-            manifest.remove(i);
-            break;
-        }
-    }
-    update_pkg_manifest(&manifest);
+    manifest.retain(|elem| elem.name != pkg.name);
+    update_pkg_manifest(&manifest)
 }
 
-/// Go through and upgrade all your installed packages.
-fn upgrade_packages(ask: bool, repo: &Option<String>) {
+/// Go through and upgrade all your installed packages, each from the repo it was installed from.
+///
+/// Upgrades are downloaded and verified first, up to `jobs` at a time. Then each one is swapped in
+/// as its own transaction, in order, so one that fails to upgrade keeps its old version and the
+/// rest still get upgraded. The first failure is returned at the end.
+fn upgrade_packages(
+        ask: bool, repo: &Option<String>, require_signed: bool, jobs: usize) -> Result<()> {
     println!("Upgrading packages...");
 
-    let mut new_manifest = Vec::new();
-    let pkg_list = pull_package_list(repo);
-    let manifest = get_pkg_manifest();
+    let repos = repos(repo)?;
+    let mut manifest = get_pkg_manifest()?;
+
+    // Packages that update themselves don't need the package list
+    let pkg_list = if manifest.iter().any(|pkg| pkg.source.is_none()) {
+        pull_package_lists(&repos, false)?
+    } else {
+        Vec::new()
+    };
+    let mut upgrades: Vec<(Package, Package)> = Vec::new();
     for inst_pkg in manifest.iter() {
-        if pkg_list.iter().any(|pkg| pkg.name == inst_pkg.name) {
-            let upstream = pkg_list.iter().find(|pkg| pkg.name == inst_pkg.name).unwrap();
-            if inst_pkg.upgradable_to(upstream) {
+        if inst_pkg.source == Some(Source::Embedded) {
+            match inst_pkg.embedded_upgrade() {
+                Ok(Some(upstream)) => {
+                    println!(
+                        "Found upgrade for '{}:' {} -> {}",
+                        inst_pkg.name, inst_pkg.version, upstream.version
+                    );
+                    if prompt("Install?", ask) {
+                        upgrades.push((inst_pkg.clone(), upstream));
+                    }
+                }, Ok(None) => {},
+                Err(err) => println!("Failed to check '{}' for updates: {}", inst_pkg.name, err)
+            }
+            continue;
+        }
+
+        // Local packages have nowhere to get a new version from
+        if inst_pkg.source == Some(Source::Local) {
+            continue;
+        }
+
+        // Stick to the repo each package was installed from
+        let pinned = inst_pkg.repo.as_deref().filter(|_| repo.is_none());
+        if let Some(pinned) = pinned {
+            if !repos.iter().any(|repo| repo.name == pinned) {
                 println!(
-                    "Found upgrade for '{}:' {} -> {}",
-                    inst_pkg.name, inst_pkg.version, upstream.version
+                    "Package '{}' is from repo '{}', which isn't configured. Skipping.",
+                    inst_pkg.name, pinned
                 );
+                continue;
+            }
+        }
+        let upstream = find_pkg(&pkg_list, &inst_pkg.name, pinned)
+            .filter(|pkg| inst_pkg.upgradable_to(pkg));
+        if let Some(upstream) = upstream {
+            println!(
+                "Found upgrade for '{}:' {} -> {}",
+                inst_pkg.name, inst_pkg.version, upstream.version
+            );
+            if prompt("Install?", ask) {
+                upgrades.push((inst_pkg.clone(), upstream.clone()));
+            }
+        }
+    }
 
-                if !prompt("Install?", ask) {
-                    new_manifest.push(inst_pkg.clone());
-                }
-
-                new_manifest.push(upstream.clone());
-                
-                println!("Downloading...");
-                upstream.download();
-
-                println!("Removing old...");
-                inst_pkg.remove();
-                
-                println!("Upgraded.");
-            } else {
-                new_manifest.push(inst_pkg.clone());
+    println!("Downloading {} packages...", upgrades.len());
+    let downloads = download_all(&upgrades, jobs, require_signed);
+
+    let mut failure = None;
+    let mut upgraded = 0;
+    for ((inst_pkg, upstream), download) in upgrades.iter().zip(downloads) {
+        match download.and_then(|_| commit_staged(&mut manifest, upstream, Some(inst_pkg))) {
+            Ok(_) => {
+                println!("Upgraded '{}' to {}.", inst_pkg.name, upstream.version);
+                upgraded += 1;
+            }, Err(err) => {
+                println!("Failed to upgrade '{}': {}", inst_pkg.name, err);
+                failure = failure.or(Some(err));
             }
-        } else {
-            new_manifest.push(inst_pkg.clone());
         }
     }
 
-    println!("Done with upgrade.");
-    update_pkg_manifest(&new_manifest);
+    println!("Done with upgrade. Upgraded {} of {} packages.", upgraded, upgrades.len());
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(())
+    }
 }
 
-/// List currently installed packages
-fn list_packages() {
-    let manifest = get_pkg_manifest();
-    for pkg in manifest {
+/// Switch packages to updating from the update information in their AppImages
+///
+/// They're no longer tied to a repo after this. Installing one from a repo again switches it back.
+fn adopt_updates(pkg_names: &[String], ask: bool) -> Result<()> {
+    let mut manifest = get_pkg_manifest()?;
+    let mut changed = false;
+    for pkg_name in pkg_names {
+        let pkg = match manifest.iter_mut().find(|pkg| pkg.name == *pkg_name) {
+            Some(pkg) => pkg,
+            None => {
+                println!("No such package '{}' installed!", pkg_name);
+                continue;
+            }
+        };
+        let update_info = match appimage::update_info(&pkg.install_path()?) {
+            Some(update_info) if is_supported(&update_info) => update_info,
+            Some(update_info) => {
+                println!("Can't update '{}' from '{}'.", pkg_name, update_info);
+                continue;
+            }, None => {
+                println!("AppImage for '{}' has no update information.", pkg_name);
+                continue;
+            }
+        };
+        if prompt(&format!("Update '{}' from '{}'?", pkg_name, update_info), ask) {
+            pkg.source = Some(Source::Embedded);
+            pkg.repo = None;
+            changed = true;
+            println!("'{}' will now be updated from '{}'.", pkg_name, update_info);
+        }
+    }
+    if changed {
+        update_pkg_manifest(&manifest)?;
+    }
+    Ok(())
+}
+
+/// Add AppImages sitting in the install directory that aren't in the manifest
+///
+/// Each one is asked about unless `yes` is set. They're renamed to `<name>-<version>.AppImage` like
+/// installed packages.
+fn adopt(yes: bool, repo: &Option<String>, offline: bool) -> Result<()> {
+    let app_dir = app_dir()?;
+    let mut manifest = get_pkg_manifest()?;
+    let tracked = manifest.iter().map(|pkg| pkg.install_path()).collect::<Result<Vec<String>>>()?;
+    let mut files: Vec<PathBuf> = read_dir(&app_dir).context("Failed to read ~/Applications")?
+        .flatten().map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "AppImage"))
+        .filter(|path| !tracked.iter().any(|tracked| Path::new(tracked) == path))
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        println!("No untracked AppImages in '{}'.", app_dir.display());
+        return Ok(());
+    }
+
+    // Matching to repos is nice to have, so don't give up without them
+    let pkg_list = pull_package_lists(&repos(repo)?, offline).unwrap_or_else(|err| {
+        println!("Warning: Can't match AppImages to repo packages: {}", err);
+        Vec::new()
+    });
+    let mut adopted = 0;
+    for file in files {
+        let file_name = match file.to_str() {
+            Some(file_name) => file_name,
+            None => {
+                println!(
+                    "Warning: Skipping '{}', since its name isn't UTF-8. Rename it to adopt it.\n",
+                    file.display()
+                );
+                continue;
+            }
+        };
+        let pkg = match identify_untracked(file_name, &pkg_list) {
+            Ok(Some(pkg)) => pkg,
+            Ok(None) => {
+                println!("Skipping '{}', which is not an AppImage.\n", file_name);
+                continue;
+            }, Err(Error::NotFound(msg)) => {
+                println!("{} Skipping it.\n", msg);
+                continue;
+            }, Err(err) => return Err(err)
+        };
         pkg.print();
-        println!();
+        if manifest.iter().any(|elem| elem.name == pkg.name) {
+            println!(
+                "Package '{}' is already installed. Leaving '{}' alone.\n", pkg.name, file_name
+            );
+            continue;
+        }
+        let msg = format!("Adopt '{}' as '{}' version '{}'?", file_name, pkg.name, pkg.version);
+        if !prompt(&msg, !yes) {
+            println!();
+            continue;
+        }
+
+        let install_path = pkg.install_path()?;
+        if Path::new(&install_path).exists() && Path::new(&install_path) != file {
+            println!("'{}' is in the way. Leaving '{}' alone.\n", install_path, file_name);
+            continue;
+        }
+        rename(&file, &install_path).context("Failed to rename AppImage")?;
+        File::open(&install_path)
+            .and_then(|out| out.set_permissions(Permissions::from_mode(PERMISSION)))
+            .context("Failed to set package permissions")?;
+        pkg.add_launchers();
+        println!("Adopted '{}' as '{}'.\n", file_name, install_path);
+        manifest.push(pkg);
+        adopted += 1;
+    }
+
+    if adopted > 0 {
+        update_pkg_manifest(&manifest)?;
+    }
+    println!("Adopted {} AppImages.", adopted);
+    Ok(())
+}
+
+/// List currently installed packages
+///
+/// The user's packages and the system's are listed separately.
+fn list_packages() -> Result<()> {
+    if !is_system() {
+        println!("User packages:\n");
+        for pkg in get_pkg_manifest()? {
+            pkg.print();
+            println!();
+        }
     }
+
+    let system_manifest = rooted(SYSTEM_DATA_DIR).join(MANIFEST_FILE);
+    if is_system() || system_manifest.exists() {
+        println!("System packages:\n");
+        let manifest = if is_system() {
+            get_pkg_manifest()?
+        } else {
+            read_manifest(&path_string(&system_manifest)?)?
+        };
+        for pkg in manifest {
+            pkg.print();
+            println!();
+        }
+    }
+    Ok(())
 }
 
 /// Execute an application
-fn run_app(app_name: &str, app_args: &Vec<String>, ask: bool) {
-    let manifest = get_pkg_manifest();
+fn run_app(app_name: &str, app_args: &Vec<String>, ask: bool) -> Result<()> {
+    let manifest = get_pkg_manifest()?;
     if !manifest.iter().any(|pkg| pkg.name == app_name) {
         println!("No such package '{}' installed!", app_name);
-        return
+        return Ok(());
     }
 
     if prompt(format!("Are you sure you want to run '{}'?", app_name).as_str(), ask) {
-        manifest.iter().find(|pkg| pkg.name == app_name).unwrap().run(app_args);
+        manifest.iter().find(|pkg| pkg.name == app_name).unwrap().run(app_args)?;
+    }
+    Ok(())
+}
+
+/// Show what's inside an installed package's AppImage or an AppImage file
+fn inspect(target: &str) -> Result<()> {
+    let manifest = get_pkg_manifest()?;
+    let file_name = match manifest.iter().find(|pkg| pkg.name == target) {
+        Some(pkg) => pkg.install_path()?,
+        None if Path::new(target).exists() => target.to_string(),
+        None => return Err(Error::NotFound(format!(
+            "No such package '{}' installed and no such file", target
+        )))
+    };
+    appimage::inspect(&file_name)?.print();
+    Ok(())
+}
+
+/// Report where the manifest and ~/Applications disagree, fixing what can be fixed if `fix` is set
+fn doctor(fix: bool, ask: bool) -> Result<()> {
+    let mut manifest = get_pkg_manifest()?;
+    let problems = doctor::check(&manifest)?;
+    if problems.is_empty() {
+        println!("No problems found.");
+        return Ok(());
+    }
+
+    let entries = manifest.len();
+    let mut fixable = 0;
+    let mut fixed = 0;
+    for problem in &problems {
+        println!("{}", problem);
+        if let Some(advice) = problem.advice() {
+            println!("  {}", advice);
+            continue;
+        }
+        fixable += 1;
+        if fix && prompt("Fix it?", ask) && problem.fix(&mut manifest)? {
+            println!("  Fixed.");
+            fixed += 1;
+        }
+    }
+
+    if manifest.len() != entries {
+        update_pkg_manifest(&manifest)?;
     }
+    if fix {
+        println!("Fixed {} of {} problems.", fixed, problems.len());
+    } else if fixable > 0 {
+        println!(
+            "Found {} problems. Run `aipman doctor --fix` to fix {} of them.",
+            problems.len(), fixable
+        );
+    } else {
+        println!("Found {} problems.", problems.len());
+    }
+    Ok(())
 }
 
 /// Get a Yes/No response from the user
@@ -227,58 +696,192 @@ fn prompt(msg: &str, ask: bool) -> bool {
         print!("{} [Y/n] ", msg);
         stdout().flush().expect("Failed to flush stdout.");
 
+        // Nobody's there to answer (e.g. stdin is closed), so don't go ahead
         response = String::new();
-        let _ = stdin().read_line(&mut response).unwrap();
+        if stdin().read_line(&mut response).unwrap_or(0) == 0 {
+            println!();
+            return false;
+        }
     }
 
-    if response.to_lowercase() == "n\n" {
-        false
-    } else {
-        true    
-    }
+    response.to_lowercase() != "n\n"
 }
 
-/// Untar the backup (if exists) to restore to a previous ~/Applications directory
-fn restore(ask: bool) {
+/// Untar the backup (if exists) to restore to a previous ~/Applications directory and manifest
+fn restore(ask: bool) -> Result<()> {
     if !prompt("Restoring will delete your current Applications folder. Continue?", ask) {
-        return;
+        return Ok(());
     }
 
     // First check if the archive exists
-    let home = home_dir()
-        .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-    let home = home.to_str().unwrap();
-    if !Path::new(&format!("{}/.aipman_backup.tar.gz", home)).exists() {
+    let backup = backup_path()?;
+    if !backup.exists() {
         println!("No backup found. Cannot restore where a backup does not exist.");
-        return;
+        return Ok(());
     }
 
-    // Then remove the current ~/Applications
+    // Then clear out the current ~/Applications
     println!("Removing corrupted ~/Applications.");
-    let mut app_dir = home_dir()
-        .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-    app_dir.push(APP_DIR);
-    remove_dir_all(app_dir)
-        .expect("Failed to remove Applications dir.");
+    let app_dir = app_dir()?;
+    for entry in read_dir(&app_dir).context("Failed to read ~/Applications")?.flatten() {
+        if entry.path().is_dir() {
+            remove_dir_all(entry.path())
+        } else {
+            remove_file(entry.path())
+        }.context("Failed to remove Applications dir")?;
+    }
+    let manifest = manifest_path()?;
+    for file_name in [ manifest.clone(), format!("{}.bak", manifest) ] {
+        if Path::new(&file_name).exists() {
+            remove_file(&file_name).context("Failed to remove manifest")?;
+        }
+    }
 
-    // Unpack the archive
+    // Unpack the archive. Backups made by older versions hold a single directory with the
+    // manifest inside, which gets moved to where it belongs afterwards.
     println!("Restoring backup...");
-    let tar_gz = File::open(&format!("{}/.aipman_backup.tar.gz", home))
-        .expect("Failed to open backup.");
+    let tar_gz = File::open(backup).context("Failed to open backup")?;
     let tar = GzDecoder::new(tar_gz);
     let mut archive = Archive::new(tar);
-    archive.unpack(home)
-        .expect("Failed to unpack backup archive.");
-    
+    let data_dir = aipman_data_dir()?;
+    for entry in archive.entries().context("Failed to read backup archive")? {
+        let mut entry = entry.context("Failed to read backup archive")?;
+        let path = entry.path().context("Failed to read backup archive")?.into_owned();
+        let mut components = path.components();
+        let dest = match components.next() {
+            Some(Component::Normal(top)) if top == "data" => &data_dir,
+            _ => &app_dir
+        };
+        let rest = components.as_path();
+        if rest.as_os_str().is_empty() {
+            continue;
+        }
+        if !rest.components().all(|component| matches!(component, Component::Normal(_))) {
+            println!("Warning: Skipping suspicious path '{}' in backup.", path.display());
+            continue;
+        }
+        let dest = dest.join(rest);
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent).context("Failed to unpack backup archive")?;
+        }
+        entry.unpack(&dest).context("Failed to unpack backup archive")?;
+    }
+    migrate_old_layout()?;
+
     println!("Complete.");
+    Ok(())
 }
 
 /// Download package list and print the packages
-fn list_available(repo: &Option<String>) {
-    let pkg_list = pull_package_list(repo);
+fn list_available(repo: &Option<String>, offline: bool) -> Result<()> {
+    let pkg_list = pull_package_list(&repos(repo)?, offline)?;
     for pkg in pkg_list {
         pkg.print();
         println!();
     }
+    Ok(())
 }
 
+/// Bring the cached package lists up to date
+fn refresh(repo: &Option<String>, offline: bool) -> Result<()> {
+    if offline {
+        return Err(Error::Network("Can't refresh the package list while offline".to_string()));
+    }
+    let repos = repos(repo)?;
+    let pkg_list = pull_package_list(&repos, false)?;
+    println!(
+        "Package lists are up to date. {} packages available from {} repos.",
+        pkg_list.len(), repos.len()
+    );
+    Ok(())
+}
+
+/// Add a key to the keyring so AppImages signed with it are trusted
+fn add_key(file_name: &str) -> Result<()> {
+    let bytes = read(file_name).context("Failed to read key file")?;
+    let fpr = import_key(&bytes)?;
+    println!("Trusting key {}.", fpr);
+    Ok(())
+}
+
+/// Remove a key from the keyring
+fn forget_key(fpr: &str) -> Result<()> {
+    remove_key(fpr)?;
+    println!("Key removed.");
+    Ok(())
+}
+
+/// Print the fingerprints and user ids of trusted keys
+fn list_keys() -> Result<()> {
+    for key in load_keys()? {
+        println!("Key:");
+        println!("| Fingerprint: {}", fingerprint(&key));
+        for user in key.details.users.iter() {
+            println!("| User: {}", String::from_utf8_lossy(user.id.id()));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Give a package another command name
+fn add_alias(pkg_name: &str, alias: &str) -> Result<()> {
+    if alias.is_empty() || alias.contains('/') || alias == "." || alias == ".." {
+        return Err(Error::Parse(format!("'{}' isn't a valid command name", alias)));
+    }
+    let manifest = get_pkg_manifest()?;
+    if manifest.iter().any(|pkg| pkg.name == alias) {
+        return Err(Error::Parse(format!("'{}' is already an installed package", alias)));
+    }
+    let taken = load_config()?.aliases.into_iter()
+        .find(|(other, aliases)| other != pkg_name && aliases.iter().any(|elem| elem == alias));
+    if let Some((other, _)) = taken {
+        return Err(Error::Parse(format!("'{}' is already an alias for '{}'", alias, other)));
+    }
+
+    config::add_alias(pkg_name, alias)?;
+    if let Some(pkg) = manifest.iter().find(|pkg| pkg.name == pkg_name) {
+        install_shim(pkg)?;
+    }
+    println!("Added alias '{}' for '{}'.", alias, pkg_name);
+    Ok(())
+}
+
+/// Remove a command name added with `alias add`
+fn remove_alias(alias: &str) -> Result<()> {
+    let pkg_name = config::remove_alias(alias)?;
+    remove_command(alias)?;
+    println!("Removed alias '{}' for '{}'.", alias, pkg_name);
+    Ok(())
+}
+
+/// List the command names added with `alias add`
+fn list_aliases() -> Result<()> {
+    for (pkg_name, aliases) in load_config()?.aliases {
+        println!("{}: {}", pkg_name, aliases.join(", "));
+    }
+    Ok(())
+}
+
+/// Print the value of a setting, after combining the system and user config
+fn get_setting(key: &str) -> Result<()> {
+    if !SETTINGS.contains(&key) {
+        return Err(Error::NotFound(format!("No such setting '{}'", key)));
+    }
+    let settings = toml::Table::try_from(load_config()?)
+        .map_err(|err| Error::Parse(format!("Failed to format config: {}", err)))?;
+    match settings.get(key) {
+        Some(toml::Value::String(value)) => println!("{}", value),
+        Some(value) => println!("{}", value),
+        None => println!("(not set)")
+    }
+    Ok(())
+}
+
+/// Print every setting, after combining the system and user config
+fn list_settings() -> Result<()> {
+    let config_text = toml::to_string(&load_config()?)
+        .map_err(|err| Error::Parse(format!("Failed to format config: {}", err)))?;
+    print!("{}", config_text);
+    Ok(())
+}
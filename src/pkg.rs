@@ -2,36 +2,83 @@
 //! Abstraction of packages, pulling them, and installing them to keep code in main simpler
 
 use std::{
-    env::consts::ARCH,
-    path::Path,
+    cmp::Reverse,
+    env::{
+        var, consts::ARCH
+    },
+    path::{
+        Path, PathBuf
+    },
     fs::{
-        File, create_dir_all, remove_file, Permissions, read, read_to_string, rename, remove_dir_all
+        File, create_dir_all, remove_file, Permissions, read, read_to_string, rename,
+        remove_dir_all, copy as copy_file, metadata
     }, io::{
-        Write, copy, BufReader, Cursor
+        Read, Write, copy, Cursor, ErrorKind
     }, process::{
-        Stdio, Command
+        self, Stdio, Command
     }, os::unix::fs::PermissionsExt,
-    collections::HashMap
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{
+            AtomicUsize, Ordering
+        }
+    }, thread
+};
+use dirs::{
+    data_dir, home_dir
 };
-use dirs::home_dir;
 use flate2::read::GzDecoder;
 use glob::glob;
-use reqwest::blocking::get;
+use reqwest::{
+    StatusCode,
+    blocking::Client,
+    header::RANGE
+};
 use serde::{
     Serialize, Deserialize
 };
+use sha2::{
+    Digest, Sha256
+};
 use serde_json::{
-    from_str, to_string_pretty, from_reader
+    from_str, from_slice, to_string_pretty
 };
 use tar::Archive;
 use version_compare::{
     compare, Cmp
 };
 use zip_extract::extract;
+use crate::{
+    appimage, cache, desktop, shim, updates, zsync,
+    config::{
+        load_config, expand_home, Repo
+    },
+    lock::LOCK_FILE,
+    error::{
+        Context, Error, Result
+    },
+    keyring::{
+        self, Verification
+    }, progress::{
+        Overall, Progress, note
+    }, scope::{
+        is_system, rooted, SYSTEM_APP_DIR, SYSTEM_DATA_DIR
+    }
+};
 
-const PKG_LIST_URL: &'static str =
+const PKG_LIST_URL: &str =
     "https://raw.githubusercontent.com/blueOkiris/aip-man-pkg-list/main/pkgs.json";
-pub const APP_DIR: &'static str = "Applications"; 
+pub const APP_DIR: &str = "Applications";
+pub const MANIFEST_FILE: &str = "aip_man_pkg_list.json";
+pub const BACKUP_FILE: &str = "backup.tar.gz";
+const OLD_BACKUP_FILE: &str = ".aipman_backup.tar.gz";
+
+/// Where an AppImage from a url is downloaded to while finding out what it is.
+pub const URL_DOWNLOAD_FILE: &str = "url-download.part";
+
+/// Environment variable that overrides where packages are installed.
+pub const HOME_VAR: &str = "AIPMAN_HOME";
 pub const PERMISSION: u32 = 0o755; // -rwxr-xr-x.
 
 /// Structure used to parse JSON info from package list.
@@ -41,11 +88,60 @@ pub struct Package {
     pub version: String,
     pub description: String,
     pub url: String,
+
+    /// CPU architecture `url` is built for, named like `std::env::consts::ARCH`.
+    pub arch: Option<String>,
     pub compressed: Option<bool>,
-    pub alt_arch_urls: Option<HashMap<String, String>>
+    pub alt_arch_urls: Option<HashMap<String, String>>,
+    pub sha256: Option<String>,
+    pub appimage_sha256: Option<String>,
+    pub signing_key: Option<String>,
+
+    /// SHA-1 of the AppImage, from the .zsync file of a release found through its update
+    /// information. Only set for upgrades of packages that update themselves.
+    #[serde(default)]
+    pub sha1: Option<String>,
+
+    /// Name of the repo the package came from. Only set for installed packages.
+    #[serde(default)]
+    pub repo: Option<String>,
+
+    /// Where updates come from if not a repo. Only set for installed packages.
+    #[serde(default)]
+    pub source: Option<Source>
+}
+
+/// Where an installed package gets updates from, other than a repo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// The update information embedded in its AppImage.
+    Embedded,
+
+    /// Nothing. It was installed straight from a url or file.
+    Local
 }
 
 impl Package {
+    /// A package that isn't from a repo and gets no updates, like one installed from a file.
+    pub fn local(name: String, version: String, description: String, url: String) -> Self {
+        Self {
+            name,
+            version,
+            description,
+            url,
+            arch: None,
+            compressed: None,
+            alt_arch_urls: None,
+            sha256: None,
+            appimage_sha256: None,
+            signing_key: None,
+            sha1: None,
+            repo: None,
+            source: Some(Source::Local)
+        }
+    }
+
     /// This isn't necessary due to Debug, and I could write a to_string, but I want to print this
     /// a lot considering `list` and `install` and stuff use it.
     pub fn print(&self) {
@@ -55,6 +151,9 @@ impl Package {
         println!("| Version: {}", self.version);
         println!("| Compressed?: {}", self.compressed.is_some() && self.compressed.unwrap());
         println!("| Url: {}", self.url);
+        if self.arch.is_some() {
+            println!("| Architecture: {}", self.arch.clone().unwrap());
+        }
         println!("| Alternative Architecture Urls:{}", if self.alt_arch_urls.is_none() {
             " None"
         } else {
@@ -65,183 +164,1023 @@ impl Package {
                 println!("| - {}: {}", arch, url);
             }
         }
+        println!("| Sha256: {}", self.sha256.clone().unwrap_or("None".to_string()));
+        if self.appimage_sha256.is_some() {
+            println!("| AppImage Sha256: {}", self.appimage_sha256.clone().unwrap());
+        }
+        if self.signing_key.is_some() {
+            println!("| Signing Key: {}", self.signing_key.clone().unwrap());
+        }
+        if self.sha1.is_some() {
+            println!("| Sha1: {}", self.sha1.clone().unwrap());
+        }
+        if self.repo.is_some() {
+            println!("| Repo: {}", self.repo.clone().unwrap());
+        }
+        match self.source {
+            Some(Source::Embedded) => println!("| Source: Embedded update information"),
+            Some(Source::Local) => println!("| Source: Local (not from a repo)"),
+            None => {}
+        }
     }
 
     /// Check if another package is a newer version.
     pub fn upgradable_to(&self, other: &Self) -> bool {
-        if self.name == other.name
-                && compare(self.clone().version, other.clone().version) == Ok(Cmp::Lt) {
-            true
-        } else {
-            false    
-        }
+        self.name == other.name
+            && compare(self.clone().version, other.clone().version) == Ok(Cmp::Lt)
+    }
+
+    /// Where this package's AppImage lives once installed.
+    pub fn install_path(&self) -> Result<String> {
+        path_string(&app_dir()?.join(format!("{}-{}.AppImage", self.name, self.version)))
+    }
+
+    /// Where the package is downloaded to. It stays here between attempts, so an interrupted
+    /// download can pick up where it left off.
+    pub fn part_path(&self) -> Result<String> {
+        Ok(format!("{}.part", self.install_path()?))
+    }
+
+    /// Where a downloaded and verified AppImage waits before being moved into its install path.
+    pub fn staging_path(&self) -> Result<String> {
+        Ok(format!("{}.staged", self.install_path()?))
     }
 
-    pub fn download(&self) {
-        // First, create the /home/AppImages directory if it doesn't exist
-        let mut app_dir = home_dir()
-            .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-        app_dir.push(APP_DIR);
-        create_dir_all(app_dir.clone()).expect("Failed to create Application path");
+    /// Download the package to its staging path in ~/Applications. See [`install_pkg`].
+    ///
+    /// If `require_signed` is set, AppImages without a valid embedded signature from a trusted key
+    /// are rejected. If the download itself fails, the partial file is kept so the next attempt can
+    /// resume it. Nothing else is left behind in ~/Applications if anything fails.
+    ///
+    /// If `old` is installed and its AppImage has zsync update information, the blocks it shares
+    /// with the new version are reused and only the rest is downloaded.
+    pub fn download(&self, require_signed: bool, old: Option<&Package>) -> Result<()> {
+        let app_dir = app_dir()?;
+        let part_path = self.part_path()?;
+        let app_image_path = self.staging_path()?;
+        let tmp_dir = path_string(&app_dir.join(format!("tmp-{}", self.name)))?;
+        let result = self.fetch(&part_path, &app_image_path, &tmp_dir, require_signed, old);
+        match &result {
+            Err(Error::Network(_)) if Path::new(&part_path).exists() =>
+                note(&format!(
+                "Keeping partial download of '{}'. Run again to resume it.", self.name
+            )),
+            Err(_) => {
+                let _ = remove_file(&part_path);
+            }, Ok(_) => {}
+        }
+        if result.is_err() {
+            let _ = remove_file(&app_image_path);
+            let _ = remove_dir_all(&tmp_dir);
+        }
+        result
+    }
 
-        // Grab the file
+    /// Does the actual work of download, leaving cleanup on failure to it.
+    fn fetch(
+            &self, part_path: &str, app_image_path: &str, tmp_dir: &str,
+            require_signed: bool, old: Option<&Package>) -> Result<()> {
+        // Grab the file, hashing the bytes as they come in so we can check them against the repo
         let url = if self.alt_arch_urls.is_some()
                     && self.alt_arch_urls.clone().unwrap().contains_key(ARCH) {
-                println!("Using architecture specific url...");
+                note(&format!("Using architecture specific url for '{}'...", self.name));
                 self.alt_arch_urls.clone().unwrap()[ARCH].clone()
             } else {
+            // No point downloading it if the repo already says it won't run here
+            if let Some(arch) = self.arch.as_ref().filter(|arch| *arch != ARCH) {
+                return Err(Error::NotFound(format!(
+                    "'{}' is only available for {}, not {}. Refusing to install it.",
+                    self.name, arch, ARCH
+                )));
+            }
             self.url.clone()
         };
-        let mut pkg_file = get(url.clone()).expect("Failed to download package");
+        let compressed = self.compressed.is_some() && self.compressed.unwrap();
 
-        // Write it
-        let app_image_path = format!(
-            "{}/{}-{}.AppImage", app_dir.as_os_str().to_str().unwrap(), self.name, self.version
-        );
-        let mut out = File::create(app_image_path.clone()).expect("Failed to save file");
-        copy(&mut pkg_file, &mut out).expect("Failed to write package content to file");
+        // A partial download is closer to done than a delta, so keep going with that
+        let delta = old.filter(|_| !compressed && !Path::new(part_path).exists())
+            .and_then(|old| self.delta_download(old, &url, part_path));
+        let hasher = match delta {
+            Some(hasher) => hasher,
+            None => {
+                let hasher = download_part(&url, part_path, &self.name)?;
+
+                // A delta has already been checked against the SHA-1, but a full download hasn't
+                if let Some(expected) = &self.sha1 {
+                    note(&format!("Verifying SHA-1 of '{}'...", self.name));
+                    let actual = zsync::file_sha1(part_path)?;
+                    if expected.to_lowercase() != actual {
+                        return Err(Error::Integrity(format!(
+                            "SHA-1 mismatch for '{}'! Expected {}, but got {}. Refusing to \
+                                install.", part_path, expected, actual
+                        )));
+                    }
+                }
+                hasher
+            }
+        };
+        if let Some(expected) = &self.sha256 {
+            note(&format!("Verifying checksum of '{}'...", self.name));
+            check_sha256(expected, &to_hex(&hasher.finalize()), part_path)?;
+        }
 
         // If it's compressed, extract it
-        if self.compressed.is_some() && self.compressed.unwrap() {
-            println!("AppImage is within archive. Extracting...");
+        if compressed {
+            note(&format!("AppImage for '{}' is within archive. Extracting...", self.name));
 
             // Extract file to ~/Applications/tmp-<name>
-            let tmp_dir = format!("{}/tmp-{}", app_dir.as_os_str().to_str().unwrap(), self.name);
             if url.ends_with(".zip") {
-                let file_contents = read(app_image_path.clone()).expect("Failed to read zip contents");
-                extract(Cursor::new(file_contents), &Path::new(&tmp_dir), true)
-                    .expect("Failed to extract zip.");
+                let file_contents = read(part_path).context("Failed to read zip contents")?;
+                extract(Cursor::new(file_contents), Path::new(tmp_dir), true)
+                    .map_err(|err| Error::Io(format!("Failed to extract zip: {}", err)))?;
             } else if url.ends_with(".gz") {
-                let tar_file = File::open(app_image_path.clone())
-                    .expect("Failed to open archive.");
+                let tar_file = File::open(part_path).context("Failed to open archive")?;
                 let tar = GzDecoder::new(tar_file);
                 let mut archive = Archive::new(tar);
-                archive.unpack(tmp_dir.clone()).expect("Failed to unpack tar archive.");        
+                archive.unpack(tmp_dir).context("Failed to unpack tar archive")?;
             }
 
-            println!("Removing archive...");
-            remove_file(app_image_path.clone()).expect("Failed to delete old archive.");
+            note(&format!("Removing archive for '{}'...", self.name));
+            remove_file(part_path).context("Failed to delete old archive")?;
 
             // Move the underlying AppImage into place
-            let entries = glob(format!("{}/*.AppImage", tmp_dir).as_str())
-                .expect("Failed to find AppImage in archive.");
-            for entry in entries {
-                match entry {
-                    Ok(path) => {
-                        println!("Setting executable flag...");
-                        let app_image_file = File::open(path.clone())
-                            .expect("Failed to set executable.");
-                        app_image_file.set_permissions(Permissions::from_mode(PERMISSION))
-                            .expect("Failed to set package permissions.");
-
-                        println!(
-                            "Moving {} to {}",
-                            path.as_os_str().to_str().unwrap(),
-                            app_image_path.clone()
-                        );
-                        rename(path, Path::new(&app_image_path.clone()))
-                            .expect("Failed to move AppImage into proper location.");
-                        break;
-                    },
-                    Err(_) => {}
-                }
+            let mut entries = glob(format!("{}/*.AppImage", tmp_dir).as_str())
+                .map_err(|err| Error::Io(format!("Failed to search archive: {}", err)))?;
+            let path = entries.find_map(|entry| entry.ok())
+                .ok_or(Error::NotFound("Failed to find AppImage in archive".to_string()))?;
+            note(&format!("Setting executable flag on '{}'...", self.name));
+            let app_image_file = File::open(path.clone()).context("Failed to set executable")?;
+            app_image_file.set_permissions(Permissions::from_mode(PERMISSION))
+                .context("Failed to set package permissions")?;
+
+            note(&format!("Moving {} to {}", path.display(), app_image_path));
+            rename(path, Path::new(app_image_path))
+                .context("Failed to move AppImage into proper location")?;
+
+            if remove_dir_all(tmp_dir).is_err() {
+                note(&format!(
+                    "Failed to remove {}. `aipman doctor --fix` can clean it up.", tmp_dir
+                ));
             }
-            
-            match remove_dir_all(tmp_dir.clone()) {
-                Ok(_) => {},
-                Err(_) => println!("Failed to remove {}. Manual intervention necessary.", tmp_dir)
+
+            // The archive checksum doesn't cover what we pulled out of it, so check that too
+            if let Some(expected) = &self.appimage_sha256 {
+                note(&format!("Verifying extracted AppImage checksum of '{}'...", self.name));
+                let mut app_image_file = File::open(app_image_path)
+                    .context("Failed to open extracted AppImage")?;
+                let mut hasher = Sha256::new();
+                copy(&mut app_image_file, &mut hasher)
+                    .context("Failed to read extracted AppImage")?;
+                check_sha256(expected, &to_hex(&hasher.finalize()), app_image_path)?;
             }
         } else {
             // Set executable flag
-            println!("Setting executable flag...");
-            out.set_permissions(Permissions::from_mode(PERMISSION))
-                .expect("Failed to set package permissions.");
+            note(&format!("Setting executable flag on '{}'...", self.name));
+            rename(part_path, app_image_path).context("Failed to move finished download")?;
+            File::open(app_image_path)
+                .and_then(|out| out.set_permissions(Permissions::from_mode(PERMISSION)))
+                .context("Failed to set package permissions")?;
+        }
+
+        // Servers sometimes hand back an error page instead of the file
+        if appimage::detect(app_image_path).is_none() {
+            let mut start = Vec::new();
+            let _ = File::open(app_image_path).map(|file| file.take(512).read_to_end(&mut start));
+            let hint = if String::from_utf8_lossy(&start).to_lowercase().contains("<html") {
+                " It looks like a web page."
+            } else {
+                ""
+            };
+            return Err(Error::Integrity(format!(
+                "Download for '{}' is not an AppImage.{} Refusing to install it.", self.name, hint
+            )));
+        }
+
+        // The url for the default architecture gets used on every other one too
+        if let Some(arch) = appimage::arch(app_image_path).filter(|arch| arch != ARCH) {
+            return Err(Error::Integrity(format!(
+                "AppImage for '{}' is built for {}, but this computer is {}. Refusing to install \
+                    it.", self.name, arch, ARCH
+            )));
         }
+
+        self.check_signature(app_image_path, require_signed)
     }
 
-    pub fn remove(&self) {
-        let mut app_dir = home_dir()
-            .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-        app_dir.push(APP_DIR);
-        match remove_file(format!(
-            "{}/{}-{}.AppImage", app_dir.as_os_str().to_str().unwrap(), self.name, self.version
-            )) {
-            Ok(_) => {},
-            Err(_) =>
-                println!("Warning: Failed to remove file. Manual intervention may be required")
+    /// Build the new AppImage at `part_path` from the installed one using the zsync file its update
+    /// information points to. Returns the hash of the new file, or None if it has no zsync info or
+    /// anything goes wrong, so the whole file can be downloaded instead.
+    fn delta_download(&self, old: &Package, url: &str, part_path: &str) -> Option<Sha256> {
+        let old_path = old.install_path().ok()?;
+        let update_info = appimage::update_info(&old_path)?;
+        if !updates::is_supported(&update_info) {
+            return None;
+        }
+        match self.zsync_fetch(&old_path, &update_info, url, part_path) {
+            Ok(hasher) => Some(hasher),
+            Err(err) => {
+                note(&format!(
+                    "Can't update '{}' with zsync: {}. Downloading the whole file...", self.name,
+                    err.to_string().trim_end_matches('.')
+                ));
+                let _ = remove_file(part_path);
+                None
+            }
         }
     }
 
-    pub fn run(&self, args: &Vec<String>) {
-        let mut app_dir = home_dir()
-            .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-        app_dir.push(APP_DIR);
-        let file_name = format!(
-            "{}/{}-{}.AppImage", app_dir.as_os_str().to_str().unwrap(), self.name, self.version
-        );
+    /// Does the actual work of delta_download, leaving the fallback to it.
+    fn zsync_fetch(
+            &self, old_path: &str, update_info: &str, url: &str,
+            part_path: &str) -> Result<Sha256> {
+        let release = updates::latest_release(update_info)?.ok_or(Error::NotFound(
+            format!("Can't update from '{}'", update_info)
+        ))?;
+        let control = zsync::Control::fetch(&release.zsync_url)?;
+
+        // Update info usually points at the latest release, which might not be the version the
+        // repo has. Without a checksum to tell, it has to be the same file as the url.
+        if self.sha256.is_none() && control.url != url {
+            return Err(Error::NotFound(format!(
+                "'{}' describes '{}', not '{}'", release.zsync_url, control.url, url
+            )));
+        }
+        let hasher = zsync::build(&control, old_path, part_path, &self.name)?;
+        if let Some(expected) = &self.sha256 {
+            check_sha256(expected, &to_hex(&hasher.clone().finalize()), part_path)?;
+        }
+        Ok(hasher)
+    }
+
+    /// Find the newest release of a package that updates itself from the update information in its
+    /// AppImage. None if it's already installed.
+    ///
+    /// A release is new if its SHA-1 isn't the installed AppImage's. The version comes from the
+    /// release, or failing that the file name, or failing that the SHA-1.
+    pub fn embedded_upgrade(&self) -> Result<Option<Package>> {
+        let path = self.install_path()?;
+        let update_info = appimage::update_info(&path).ok_or(Error::NotFound(format!(
+            "AppImage for '{}' has no update information", self.name
+        )))?;
+        let release = updates::latest_release(&update_info)?.ok_or(Error::NotFound(format!(
+            "Can't update '{}' from '{}'", self.name, update_info
+        )))?;
+        let control = zsync::Control::fetch(&release.zsync_url)?;
+        if zsync::file_sha1(&path)? == control.sha1 {
+            return Ok(None);
+        }
+
+        let short_sha1 = control.sha1.get(..8).unwrap_or(&control.sha1);
+        let mut version = release.version
+            .or(control.file_name.as_deref().and_then(updates::version_from_file_name))
+            .unwrap_or_else(|| short_sha1.to_string());
+        if version == self.version {
+            version = format!("{}+{}", version, short_sha1);
+        }
+        Ok(Some(Package {
+            version,
+            url: control.url,
+            sha1: Some(control.sha1),
+            arch: None,
+            compressed: None,
+            alt_arch_urls: None,
+            sha256: None,
+            appimage_sha256: None,
+            repo: None,
+            ..self.clone()
+        }))
+    }
+
+    /// Check the signature embedded in a downloaded AppImage.
+    ///
+    /// A bad signature is always rejected. A missing one or one from a key we don't trust is only
+    /// rejected when signatures are required or the repo pins a signing key for the package.
+    fn check_signature(&self, app_image_path: &str, require_signed: bool) -> Result<()> {
+        let strict = require_signed || self.signing_key.is_some();
+        let reject = |reason: String| -> Result<()> {
+            Err(Error::Integrity(format!("{} Refusing to install '{}'.", reason, self.name)))
+        };
+
+        let signature = match appimage::read_section(app_image_path, appimage::SIG_SECTION) {
+            Some(signature) => signature,
+            None => {
+                if strict {
+                    return reject(format!("AppImage for '{}' is not signed!", self.name));
+                }
+                note(&format!(
+                    "AppImage for '{}' is not signed. Skipping signature check.", self.name
+                ));
+                return Ok(());
+            }
+        };
+
+        // Trust the keyring, plus the key shipped inside the AppImage if the repo pinned it
+        note(&format!("Verifying signature of '{}'...", self.name));
+        let pinned = self.signing_key.as_ref().map(|fpr| keyring::normalize_fingerprint(fpr));
+        let mut keys = keyring::load_keys()?;
+        if let Some(embedded) = appimage::read_section(app_image_path, appimage::KEY_SECTION)
+                .and_then(|bytes| keyring::parse_key(&bytes)) {
+            if pinned == Some(keyring::fingerprint(&embedded)) {
+                keys.push(embedded);
+            }
+        }
+        if let Some(pinned) = &pinned {
+            keys.retain(|key| keyring::fingerprint(key) == *pinned);
+        }
+
+        let digest = appimage::signature_digest(app_image_path)
+            .ok_or(Error::Io("Failed to read AppImage for signature check".to_string()))?;
+        match keyring::verify(&signature, digest.as_bytes(), &keys) {
+            Verification::Valid(fpr) =>
+                note(&format!("Good signature on '{}' from {}.", self.name, fpr)),
+            Verification::Invalid(fpr) => return reject(format!("Bad signature from {}!", fpr)),
+            Verification::Malformed =>
+                return reject("Embedded signature is malformed!".to_string()),
+            Verification::UnknownKey => {
+                if strict {
+                    return reject(format!(
+                        "AppImage is not signed by {}!",
+                        pinned.unwrap_or("a trusted key".to_string())
+                    ));
+                }
+                note(&format!(
+                    "Warning: AppImage for '{}' is signed by a key that isn't in the keyring.",
+                    self.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the package's AppImage. It already being gone isn't an error.
+    pub fn remove(&self) -> Result<()> {
+        match remove_file(self.install_path()?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(Error::Io(format!(
+                "Failed to remove '{}'. Run `aipman doctor` to check on it: {}", self.name, err
+            ))), _ => Ok(())
+        }
+    }
+
+    /// Put a launcher on the PATH and a desktop entry in the menu for the package, where the
+    /// current scope calls for them. Failing to is only a warning, since the package still works.
+    pub fn add_launchers(&self) {
+        let result = shim::install_shim(self).and_then(|shim| match shim {
+            Some(shim) => desktop::install_entry(self, &shim),
+            None => desktop::install_entry(self, Path::new(&self.install_path()?))
+        });
+        if let Err(err) = result {
+            println!("Warning: Failed to add launchers for '{}': {}", self.name, err);
+        }
+    }
+
+    /// Remove the package's launcher and desktop entry, if it has them.
+    pub fn remove_launchers(&self) {
+        let result = shim::remove_shim(self).and_then(|_| desktop::remove_entry(self));
+        if let Err(err) = result {
+            println!("Warning: Failed to remove launchers for '{}': {}", self.name, err);
+        }
+    }
 
-        Command::new(file_name).args(args)
+    pub fn run(&self, args: &Vec<String>) -> Result<()> {
+        Command::new(self.install_path()?).args(args)
             .stdin(Stdio::inherit())
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
-            .output().expect("Failed to start app");
+            .output().context("Failed to start app")?;
+        Ok(())
+    }
+}
+
+/// Download `url` into `part_path`, resuming a previous attempt with a range request if the
+/// server supports it. Returns the hash of the whole file, including any part that was resumed.
+fn download_part(url: &str, part_path: &str, name: &str) -> Result<Sha256> {
+    // reqwest only speaks http, so local files get copied
+    if let Some(path) = url.strip_prefix("file://") {
+        return copy_part(path, part_path);
     }
+
+    let client = Client::new();
+    let existing = metadata(part_path).map(|meta| meta.len()).unwrap_or(0);
+    let mut response = if existing > 0 {
+        client.get(url).header(RANGE, format!("bytes={}-", existing)).send()
+    } else {
+        client.get(url).send()
+    }.context("Failed to download package")?;
+
+    // Anything but a partial response means starting over from the beginning
+    let resuming = existing > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        response = client.get(url).send().context("Failed to download package")?;
+    }
+    let mut response = response.error_for_status().context("Failed to download package")?;
+
+    let mut hasher = Sha256::new();
+    let mut out = if resuming {
+        note(&format!("Resuming download of '{}' at {} bytes...", name, existing));
+        let mut out = File::options().read(true).append(true).open(part_path)
+            .context("Failed to open partial download")?;
+        copy(&mut out, &mut hasher).context("Failed to read partial download")?;
+        out
+    } else {
+        File::create(part_path).context("Failed to save file")?
+    };
+
+    let already = if resuming { existing } else { 0 };
+    let mut progress = Progress::new(
+        name, response.content_length().map(|len| len + already), already
+    );
+    let mut buf = [0; 8192];
+    loop {
+        let len = response.read(&mut buf).map_err(|err| Error::Network(
+            format!("Failed to download package content: {}", err)
+        ))?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        out.write_all(&buf[..len]).context("Failed to write package content to file")?;
+        progress.inc(len as u64);
+    }
+    progress.finish();
+    Ok(hasher)
+}
+
+/// Copy a local file into `part_path`, hashing it on the way like a download.
+fn copy_part(path: &str, part_path: &str) -> Result<Sha256> {
+    let mut file = File::open(path).context(&format!("Failed to open '{}'", path))?;
+    let mut out = File::create(part_path).context("Failed to save file")?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        let len = file.read(&mut buf).context(&format!("Failed to read '{}'", path))?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        out.write_all(&buf[..len]).context("Failed to write package content to file")?;
+    }
+    Ok(hasher)
+}
+
+/// Download `url` to `file_name` without installing anything, e.g. to see what it is first.
+pub fn download_file(url: &str, file_name: &str, name: &str) -> Result<()> {
+    let _ = remove_file(file_name);
+    let result = download_part(url, file_name, name);
+    if result.is_err() {
+        let _ = remove_file(file_name);
+    }
+    result.map(|_| ())
+}
+
+/// Install a package and record it in the manifest as one transaction, replacing `old` if given.
+///
+/// The new version is downloaded and verified to a staging file first. Then it's committed with
+/// [`commit_staged`].
+pub fn install_pkg(
+        manifest: &mut Vec<Package>, pkg: &Package, old: Option<&Package>,
+        require_signed: bool) -> Result<()> {
+    pkg.download(require_signed, old)?;
+    commit_staged(manifest, pkg, old)
+}
+
+/// Download new versions of several installed packages to their staging paths, with up to `jobs`
+/// downloads at a time. Each pair is the installed package and the version to download.
+///
+/// The results line up with `upgrades`. Each package that downloaded fine still needs to be
+/// committed with [`commit_staged`], which should be done one at a time, in order.
+pub fn download_all(
+        upgrades: &[(Package, Package)], jobs: usize, require_signed: bool) -> Vec<Result<()>> {
+    let next = AtomicUsize::new(0);
+    let overall = Overall::new(upgrades.len());
+    let results = Mutex::new(upgrades.iter().map(|_| None).collect::<Vec<Option<Result<()>>>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, upgrades.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= upgrades.len() {
+                    break;
+                }
+                let (old, pkg) = &upgrades[i];
+                let result = pkg.download(require_signed, Some(old));
+                note(&format!(
+                    "[{}/{}] {} '{}'.",
+                    overall.finish_one(), upgrades.len(),
+                    if result.is_ok() { "Downloaded" } else { "Failed to download" }, pkg.name
+                ));
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().map(|result| result.unwrap()).collect()
 }
 
-/// Pull the package list and parse it into our abstraction.
+/// Move a staged package into place and record it in the manifest, replacing `old` if given.
 ///
-/// Note that this is used to hide complexity from the top level functions, so it cannot return a
-/// result/error. All errors must be handled here.
-pub fn pull_package_list(repo: &Option<String>) -> Vec<Package> {
-    let url = repo.clone().unwrap_or(PKG_LIST_URL.to_string());
-    if url.as_str().starts_with("file://") {
-        // Local instead
-        let file = File::open(url.split_at(7).1)
-            .expect(&format!("Failed to open local repo '{}'", url));
-        let reader = BufReader::new(file);
-        from_reader(reader).expect("Failed to parse local package list.")
+/// The old AppImage is moved aside, the new one is moved into place, and the manifest is saved. If
+/// any of that fails, the old AppImage is put back and the manifest (both on disk and `manifest`)
+/// is left as it was.
+pub fn commit_staged(
+        manifest: &mut Vec<Package>, pkg: &Package, old: Option<&Package>) -> Result<()> {
+    let staged = pkg.staging_path()?;
+    let result = swap_in(manifest, pkg, old, &staged);
+    let _ = remove_file(&staged);
+    if result.is_ok() {
+        pkg.add_launchers();
+    }
+    result
+}
+
+/// Does the work of commit_staged, rolling back on failure.
+fn swap_in(
+        manifest: &mut Vec<Package>, pkg: &Package, old: Option<&Package>,
+        staged: &str) -> Result<()> {
+    // Move the old version aside rather than deleting it, so it can be put back
+    let mut set_aside = None;
+    if let Some(old) = old {
+        let old_path = old.install_path()?;
+        if Path::new(&old_path).exists() {
+            let aside_path = format!("{}.old", old_path);
+            rename(&old_path, &aside_path).context("Failed to move old version aside")?;
+            set_aside = Some((old_path, aside_path));
+        }
+    }
+
+    // The new entry takes the old one's place in the manifest
+    let mut new_manifest = manifest.clone();
+    let replaced_name = old.map(|old| old.name.clone()).unwrap_or(pkg.name.clone());
+    match new_manifest.iter().position(|elem| elem.name == replaced_name) {
+        Some(i) => new_manifest[i] = pkg.clone(),
+        None => new_manifest.push(pkg.clone())
+    }
+
+    let new_path = pkg.install_path()?;
+    let result = rename(staged, &new_path).context("Failed to move new version into place")
+        .and_then(|_| update_pkg_manifest(&new_manifest));
+    match result {
+        Ok(_) => {
+            *manifest = new_manifest;
+            if let Some((_, aside_path)) = set_aside {
+                if remove_file(&aside_path).is_err() {
+                    println!("Warning: Failed to remove old version '{}'.", aside_path);
+                }
+            }
+            Ok(())
+        }, Err(err) => {
+            println!("Rolling back '{}'...", pkg.name);
+            let _ = remove_file(&new_path);
+            if let Some((old_path, aside_path)) = set_aside {
+                if rename(&aside_path, &old_path).is_err() {
+                    println!(
+                        "Warning: Failed to restore '{}' from '{}'. Manual intervention necessary.",
+                        old_path, aside_path
+                    );
+                }
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Compare a downloaded file's hash against the one the repo lists for it.
+fn check_sha256(expected: &str, actual: &str, file_name: &str) -> Result<()> {
+    if expected.trim().to_lowercase() != actual {
+        return Err(Error::Integrity(format!(
+            "Checksum mismatch for '{}'! Expected {}, but got {}. Refusing to install.",
+            file_name, expected, actual
+        )));
+    }
+    Ok(())
+}
+
+/// Get the hex SHA-256 of a file, like the package list has.
+pub fn file_sha256(file_name: &str) -> Result<String> {
+    let mut file = File::open(file_name).context(&format!("Failed to open '{}'", file_name))?;
+    let mut hasher = Sha256::new();
+    copy(&mut file, &mut hasher).context(&format!("Failed to read '{}'", file_name))?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Format a digest the way sha256sum and the package list do.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A path as a string, for the places that pass paths around as `&str`. Paths that aren't UTF-8
+/// are an error instead of a panic.
+pub fn path_string(path: &Path) -> Result<String> {
+    path.to_str().map(|path| path.to_string())
+        .ok_or(Error::Io(format!("Path '{}' isn't valid UTF-8", path.display())))
+}
+
+/// The directory packages are installed to. This is `$AIPMAN_HOME` if it's set, then the config's
+/// `install_dir`, and ~/Applications otherwise. Created if it doesn't exist.
+///
+/// System packages go in the config's `system_install_dir`, or /opt/appimages.
+pub fn app_dir() -> Result<PathBuf> {
+    let install_dir = if is_system() {
+        Some(load_config()?.system_install_dir.unwrap_or(SYSTEM_APP_DIR.to_string()))
+    } else {
+        var(HOME_VAR).ok().filter(|dir| !dir.is_empty()).or(load_config()?.install_dir)
+    };
+    let app_dir = rooted(match install_dir {
+        Some(install_dir) => expand_home(&install_dir)?,
+        None => {
+            let mut app_dir = home_dir().ok_or(Error::missing_dir("home"))?;
+            app_dir.push(APP_DIR);
+            app_dir
+        }
+    });
+    create_dir_all(app_dir.clone()).context("Failed to create Application path")?;
+    Ok(app_dir)
+}
+
+/// Directory holding the manifest and backups, $XDG_DATA_HOME/aipman, or /var/lib/aipman for
+/// system packages. Created if it doesn't exist.
+pub fn aipman_data_dir() -> Result<PathBuf> {
+    let dir = if is_system() {
+        PathBuf::from(SYSTEM_DATA_DIR)
     } else {
-        let list_json = get(url).expect("Failed to download package list")
-            .text().expect("Failed to get package list text");
-        from_str(list_json.as_str()).expect("Failed to parse package list.")
+        let mut dir = data_dir().ok_or(Error::missing_dir("data"))?;
+        dir.push("aipman");
+        dir
+    };
+    let dir = rooted(dir);
+    create_dir_all(dir.clone()).context("Failed to create data path")?;
+    Ok(dir)
+}
+
+/// Where `--backup` saves the backup that `restore` restores.
+pub fn backup_path() -> Result<PathBuf> {
+    let mut file_name = aipman_data_dir()?;
+    file_name.push(BACKUP_FILE);
+    Ok(file_name)
+}
+
+/// Move the manifest and backup from where older versions kept them (the install directory and
+/// the home directory) to the data directory. Nothing is moved if there's already one there. The
+/// lock file they left in the install directory is removed.
+pub fn migrate_old_layout() -> Result<()> {
+    // The lock is next to the manifest now
+    let _ = remove_file(app_dir()?.join(LOCK_FILE));
+    if is_system() {
+        return Ok(());
+    }
+
+    let old_manifest = app_dir()?.join(MANIFEST_FILE);
+    let new_manifest = PathBuf::from(manifest_path()?);
+    if old_manifest.exists() && !new_manifest.exists() {
+        println!("Moving manifest to '{}'.", new_manifest.display());
+        let old_backup = old_manifest.with_file_name(format!("{}.bak", MANIFEST_FILE));
+        if old_backup.exists() {
+            move_file(&old_backup, &new_manifest.with_file_name(format!("{}.bak", MANIFEST_FILE)))?;
+        }
+        move_file(&old_manifest, &new_manifest)?;
     }
+
+    let home = home_dir().ok_or(Error::missing_dir("home"))?;
+    let old_backup = rooted(home.join(OLD_BACKUP_FILE));
+    let new_backup = backup_path()?;
+    if old_backup.exists() && !new_backup.exists() {
+        println!("Moving backup to '{}'.", new_backup.display());
+        move_file(&old_backup, &new_backup)?;
+    }
+    Ok(())
 }
 
-/// Read (or create) the installed package manifest
-pub fn get_pkg_manifest() -> Vec<Package> {
-    // First, create the /home/AppImages directory if it doesn't exist
-    let mut app_dir = home_dir()
-        .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-    app_dir.push(APP_DIR);
-    create_dir_all(app_dir.clone()).expect("Failed to create Application path");
+/// Rename a file, or copy it and delete the original if it's going to another filesystem.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_file(from, to).context(&format!("Failed to move '{}'", from.display()))?;
+    remove_file(from).context(&format!("Failed to remove '{}'", from.display()))
+}
 
-    let file_name = format!("{}/aip_man_pkg_list.json", app_dir.as_os_str().to_str().unwrap());
+/// The repos to pull packages from, highest priority first.
+///
+/// A `repo_url` given on the command line replaces the configured repos. If none are configured,
+/// the global package list is used.
+pub fn repos(repo_url: &Option<String>) -> Result<Vec<Repo>> {
+    if let Some(url) = repo_url {
+        return Ok(vec![ Repo {
+            name: url.clone(),
+            url: url.clone(),
+            priority: 0,
+            trusted: false,
+            signing_key: None,
+            require_signature: false
+        } ]);
+    }
+
+    let mut repos = load_config()?.repos;
+    if repos.is_empty() {
+        repos.push(Repo {
+            name: "global".to_string(),
+            url: PKG_LIST_URL.to_string(),
+            priority: 0,
+            trusted: false,
+            signing_key: None,
+            require_signature: false
+        });
+    }
+    repos.sort_by_key(|repo| Reverse(repo.priority));
+    Ok(repos)
+}
+
+/// Pull the package lists of every repo, highest priority first, with each package's repo set.
+///
+/// Packages with the same name in several repos are all kept. A repo that fails is skipped with a
+/// warning, unless they all fail.
+pub fn pull_package_lists(repos: &[Repo], offline: bool) -> Result<Vec<Package>> {
+    let mut pkg_list = Vec::new();
+    let mut failure = None;
+    for repo in repos {
+        match pull_repo(repo, offline) {
+            Ok(pkgs) => pkg_list.extend(pkgs),
+            Err(err) => {
+                if repos.len() > 1 {
+                    println!("Warning: Skipping repo '{}': {}", repo.name, err);
+                }
+                failure = failure.or(Some(err));
+            }
+        }
+    }
+    match failure {
+        Some(err) if pkg_list.is_empty() => Err(err),
+        _ => Ok(pkg_list)
+    }
+}
+
+/// Pull the package lists of every repo and merge them, keeping the highest priority package when
+/// several repos have one with the same name.
+pub fn pull_package_list(repos: &[Repo], offline: bool) -> Result<Vec<Package>> {
+    let mut pkg_list: Vec<Package> = Vec::new();
+    for pkg in pull_package_lists(repos, offline)? {
+        if !pkg_list.iter().any(|elem| elem.name == pkg.name) {
+            pkg_list.push(pkg);
+        }
+    }
+    Ok(pkg_list)
+}
+
+/// Find a package in a list from [`pull_package_lists`], only looking in `repo` if given.
+pub fn find_pkg<'a>(
+        pkg_list: &'a [Package], name: &str, repo: Option<&str>) -> Option<&'a Package> {
+    pkg_list.iter().find(|pkg| {
+        pkg.name == name && (repo.is_none() || pkg.repo.as_deref() == repo)
+    })
+}
+
+/// Pull a single repo's package list and parse it into our abstraction.
+///
+/// Remote lists are cached, and only downloaded again if they've changed. With `offline`, only the
+/// cached list is used. If the repo has a detached signature next to the list (`<url>.sig`), the
+/// list is checked against the keyring before being used.
+fn pull_repo(repo: &Repo, offline: bool) -> Result<Vec<Package>> {
+    let list_json = fetch_repo_file(&repo.url, offline)?
+        .ok_or(Error::NotFound(format!("Failed to get package list '{}'", repo.url)))?;
+    let signature = fetch_repo_file(&format!("{}.sig", repo.url), offline)?;
+    check_repo_signature(repo, &list_json, signature)?;
+    let mut pkgs: Vec<Package> = from_slice(&list_json).context("Failed to parse package list")?;
+    for pkg in pkgs.iter_mut() {
+        pkg.repo = Some(repo.name.clone());
+    }
+    Ok(pkgs)
+}
+
+/// Read a file from a repo, either local via file:// or remote. Returns None if it doesn't exist.
+fn fetch_repo_file(url: &str, offline: bool) -> Result<Option<Vec<u8>>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+        Ok(Some(read(path).context(&format!("Failed to open local repo file '{}'", url))?))
+    } else {
+        cache::fetch(url, offline)
+    }
+}
+
+/// Make sure a package list is signed by a key in the keyring.
+///
+/// A bad signature is never accepted. If the repo has a `signing_key` or `require_signature`, a
+/// missing signature or one from any other key is refused too. Otherwise unsigned lists (or ones
+/// signed by a key we don't know) still work, but we warn about them unless the repo is trusted.
+fn check_repo_signature(repo: &Repo, list_json: &[u8], signature: Option<Vec<u8>>) -> Result<()> {
+    let url = &repo.url;
+    let strict = repo.require_signature || repo.signing_key.is_some();
+    let trusted = repo.trusted || load_config()?.trusted_repos.iter().any(|trusted| trusted == url);
+    let signature = match signature {
+        Some(signature) => signature,
+        None if strict => return Err(Error::Integrity(format!(
+            "Package list '{}' is not signed! Refusing to use it.", url
+        ))), None => {
+            if !trusted {
+                println!("Warning: Package list '{}' is not signed.", url);
+            }
+            return Ok(());
+        }
+    };
+
+    // A pinned repo key is the only one that counts, so other keys in the keyring can't sign for it
+    let mut keys = keyring::load_keys()?;
+    if let Some(pinned) = &repo.signing_key {
+        let pinned = keyring::normalize_fingerprint(pinned);
+        keys.retain(|key| keyring::fingerprint(key) == pinned);
+        if keys.is_empty() {
+            return Err(Error::NotFound(format!(
+                "Signing key {} for package list '{}' isn't in the keyring. Add it with \
+                    `aipman key add`.", pinned, url
+            )));
+        }
+    }
+    match keyring::verify(&signature, list_json, &keys) {
+        Verification::Valid(_) => {},
+        Verification::Invalid(fpr) => return Err(Error::Integrity(format!(
+            "Package list '{}' has a bad signature from {}! Refusing to use it.", url, fpr
+        ))), Verification::Malformed => return Err(Error::Integrity(format!(
+            "Package list '{}' has a malformed signature! Refusing to use it.", url
+        ))), Verification::UnknownKey if strict => return Err(Error::Integrity(format!(
+            "Package list '{}' isn't signed by {}! Refusing to use it.", url,
+            repo.signing_key.clone().unwrap_or("a key in the keyring".to_string())
+        ))), Verification::UnknownKey => if !trusted {
+            println!(
+                "Warning: Package list '{}' is signed by a key that isn't in the keyring.", url
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where the manifest of installed packages is kept.
+pub fn manifest_path() -> Result<String> {
+    path_string(&aipman_data_dir()?.join(MANIFEST_FILE))
+}
+
+/// Read (or create) the installed package manifest
+pub fn get_pkg_manifest() -> Result<Vec<Package>> {
+    let file_name = manifest_path()?;
+    let backup_name = format!("{}.bak", file_name);
 
     // Create the manifest if it doesn't exist
-    if !Path::new(&file_name).exists() {
+    if !Path::new(&file_name).exists() && !Path::new(&backup_name).exists() {
         println!("Local manifest does not exist. Creating...");
-        let mut output = File::create(file_name.clone()).expect("Failed to create manifest");
-        write!(output, "[\n]").expect("Failed to write to new manifest file");
+        write_atomic(&file_name, b"[\n]").context("Failed to create manifest")?;
     }
+    read_manifest(&file_name)
+}
 
-    let manifest_text = read_to_string(file_name).expect("Failed to read manifest");
-    from_str(&manifest_text).expect("Failed to parse manifest file")
+/// Read a package manifest.
+///
+/// If the manifest is missing or can't be parsed, but the backup made by the last successful
+/// update is fine, the backup is used instead.
+pub fn read_manifest(file_name: &str) -> Result<Vec<Package>> {
+    let backup_name = format!("{}.bak", file_name);
+    let manifest = read_to_string(file_name).context("Failed to read manifest")
+        .and_then(|text| from_str(&text).context("Failed to parse manifest file"));
+    match manifest {
+        Err(err) if Path::new(&backup_name).exists() => {
+            println!("Warning: {}. Falling back to the backup manifest.", err);
+            let backup_text = read_to_string(&backup_name)
+                .context("Failed to read backup manifest")?;
+            from_str(&backup_text).context("Failed to parse backup manifest file")
+        }, manifest => manifest
+    }
 }
 
 /// Overwrite the manifest with new data
-pub fn update_pkg_manifest(manifest: &Vec<Package>) {
-    // First, create the /home/AppImages directory if it doesn't exist
-    let mut app_dir = home_dir()
-        .expect("Um. Somehow you don't have a home directory. You can't use this tool");
-    app_dir.push(APP_DIR);
-    create_dir_all(app_dir.clone()).expect("Failed to create Application path");
-
-    let file_name = format!("{}/aip_man_pkg_list.json", app_dir.as_os_str().to_str().unwrap());
+///
+/// The old manifest is kept as a backup, and the new one is swapped in atomically, so a crash or a
+/// full disk can never leave behind a truncated manifest.
+pub fn update_pkg_manifest(manifest: &Vec<Package>) -> Result<()> {
+    let file_name = manifest_path()?;
 
-    // Create the manifest if it doesn't exist
     println!("Updating manifest...");
-    let manifest_json = to_string_pretty(manifest).expect("Failed to format new manifest");
-    let mut output = File::create(file_name.clone()).expect("Failed to open manifest for writing");
-    write!(output, "{}", manifest_json).expect("Failed to save manifest");
+    let manifest_json = to_string_pretty(manifest).context("Failed to format new manifest")?;
+
+    // Don't let a corrupt manifest replace a good backup
+    let current_is_valid = read_to_string(&file_name).ok()
+        .and_then(|text| from_str::<Vec<Package>>(&text).ok()).is_some();
+    if current_is_valid {
+        copy_file(&file_name, format!("{}.bak", file_name))
+            .context("Failed to back up manifest")?;
+    }
+    write_atomic(&file_name, manifest_json.as_bytes()).context("Failed to save manifest")
+}
+
+/// Write a file by writing and syncing a temporary file next to it, then renaming it into place.
+///
+/// The temporary file is named after our process, so two aipmans writing the same file at once
+/// can't write into each other's.
+pub fn write_atomic(file_name: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    let file_name = file_name.as_ref();
+    let mut tmp_name = file_name.as_os_str().to_owned();
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let result = File::create(&tmp_name).and_then(|mut output| {
+        output.write_all(contents)?;
+        output.sync_all()
+    }).and_then(|_| rename(&tmp_name, file_name));
+    if result.is_err() {
+        let _ = remove_file(&tmp_name);
+    }
+    result?;
+
+    // Make sure the rename itself makes it to disk
+    if let Some(dir) = file_name.parent() {
+        File::open(dir).and_then(|dir| dir.sync_all())?;
+    }
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+    use sha1::Sha1;
+    use crate::testing::{
+        Server, app_home, fake_appimage, zsync_file
+    };
+    use super::*;
+
+    #[test]
+    fn rejects_sha256_mismatch() {
+        let home = app_home();
+        let server = Server::new(false);
+        let appimage = fake_appimage("");
+        server.add("Sum-1.0.AppImage", appimage.clone());
+        let pkg = Package {
+            sha256: Some("0".repeat(64)),
+            ..Package::local(
+                "sha256-test".to_string(), "1.0".to_string(), String::new(),
+                format!("{}/Sum-1.0.AppImage", server.url)
+            )
+        };
+        assert!(matches!(pkg.download(false, None), Err(Error::Integrity(_))));
+        assert!(!Path::new(&pkg.part_path().unwrap()).exists());
+        assert!(!Path::new(&pkg.staging_path().unwrap()).exists());
+        assert!(!home.join("tmp-sha256-test").exists());
+
+        // Repos can write the checksum in upper case
+        let sha256 = to_hex(&Sha256::digest(&appimage)).to_uppercase();
+        let pkg = Package { sha256: Some(sha256), ..pkg };
+        pkg.download(false, None).unwrap();
+        remove_file(pkg.staging_path().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn checks_sha1_of_full_downloads() {
+        app_home();
+        let server = Server::new(false);
+        let appimage = fake_appimage("");
+        server.add("Sha1-1.0.AppImage", appimage.clone());
+        let pkg = Package {
+            sha1: Some("0".repeat(40)),
+            ..Package::local(
+                "sha1-test".to_string(), "1.0".to_string(), String::new(),
+                format!("{}/Sha1-1.0.AppImage", server.url)
+            )
+        };
+        assert!(matches!(pkg.download(false, None), Err(Error::Integrity(_))));
+        assert!(!Path::new(&pkg.part_path().unwrap()).exists());
+        assert!(!Path::new(&pkg.staging_path().unwrap()).exists());
+
+        let pkg = Package { sha1: Some(to_hex(&Sha1::digest(&appimage))), ..pkg };
+        pkg.download(false, None).unwrap();
+        remove_file(pkg.staging_path().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn embedded_upgrade_skips_installed_release() {
+        app_home();
+        let server = Server::new(false);
+        let pkg = Package {
+            source: Some(Source::Embedded),
+            ..Package::local(
+                "app".to_string(), "1.0".to_string(), "An app".to_string(),
+                format!("{}/App-1.0-x86_64.AppImage", server.url)
+            )
+        };
+        let installed = fake_appimage(&format!("zsync|{}/App.AppImage.zsync", server.url));
+        write(pkg.install_path().unwrap(), &installed).unwrap();
+
+        let control = zsync_file(&installed, 2048, 1, "App-1.0-x86_64.AppImage");
+        server.add("App.AppImage.zsync", control);
+        assert!(pkg.embedded_upgrade().unwrap().is_none());
+
+        // Anything else is an upgrade, with the version from its file name
+        let mut newer = installed.clone();
+        newer.extend(b"more");
+        let control = zsync_file(&newer, 2048, 1, "App-1.1-x86_64.AppImage");
+        server.add("App.AppImage.zsync", control);
+        let upgrade = pkg.embedded_upgrade().unwrap().unwrap();
+        assert_eq!(upgrade.version, "1.1");
+        assert_eq!(upgrade.sha1, Some(to_hex(&Sha1::digest(&newer))));
+        assert_eq!(upgrade.url, format!("{}/App-1.1-x86_64.AppImage", server.url));
+
+        // A broken .zsync file is an error, not a crash
+        let control = format!(
+            "zsync: 0.6.2\nBlocksize: 2048\nLength: 4\nURL: App.AppImage\nSHA-1: 00\n\n{}",
+            "x".repeat(20)
+        );
+        server.add("App.AppImage.zsync", control);
+        assert!(matches!(pkg.embedded_upgrade(), Err(Error::Parse(_))));
+    }
+}
@@ -0,0 +1,212 @@
+// Author(s): Dylan Turner
+//! Show how a download is coming along, as a bar on a terminal or as periodic lines otherwise
+
+use std::{
+    io::{
+        stdout, IsTerminal
+    }, sync::{
+        Mutex, OnceLock,
+        atomic::{
+            AtomicUsize, Ordering
+        }
+    }, time::{
+        Duration, Instant
+    }
+};
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle
+};
+
+/// How often to print a progress line when we can't draw a bar.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Every bar is drawn through this, so several downloads at once stack instead of fighting.
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Bar for the whole batch of downloads going on right now, if there is one. See [`Overall`].
+static OVERALL: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+fn bars() -> &'static MultiProgress {
+    BARS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stdout()))
+}
+
+/// Print a line without garbling any progress bars that are being drawn.
+pub fn note(msg: &str) {
+    if stdout().is_terminal() {
+        let _ = bars().println(msg);
+    } else {
+        println!("{}", msg);
+    }
+}
+
+/// Progress of a batch of downloads together, drawn as a bar under the ones for each download.
+///
+/// Every [`Progress`] started while this is alive adds its size to the total and its bytes to the
+/// bar, so the total grows as downloads start.
+pub struct Overall {
+    bar: Option<ProgressBar>,
+    count: usize,
+    finished: AtomicUsize
+}
+
+impl Overall {
+    /// Start tracking a batch of `count` downloads.
+    pub fn new(count: usize) -> Self {
+        let bar = if stdout().is_terminal() {
+            let bar = bars().add(ProgressBar::new(0).with_style(ProgressStyle::with_template(
+                "Total {msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}"
+            ).unwrap().progress_chars("=> ")));
+            bar.set_message(format!("[0/{}]", count));
+            Some(bar)
+        } else {
+            None
+        };
+        if let Ok(mut overall) = OVERALL.lock() {
+            *overall = bar.clone();
+        }
+        Self { bar, count, finished: AtomicUsize::new(0) }
+    }
+
+    /// Record that one of the downloads is over, whether it worked or not. Returns how many are.
+    pub fn finish_one(&self) -> usize {
+        let finished = self.finished.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(bar) = &self.bar {
+            bar.set_message(format!("[{}/{}]", finished, self.count));
+        }
+        finished
+    }
+}
+
+impl Drop for Overall {
+    fn drop(&mut self) {
+        if let Ok(mut overall) = OVERALL.lock() {
+            *overall = None;
+        }
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+    }
+}
+
+/// Progress of a single download.
+pub struct Progress {
+    name: String,
+    bar: Option<ProgressBar>,
+    overall: Option<ProgressBar>,
+    total: Option<u64>,
+    done: u64,
+    resumed: u64,
+    started: Instant,
+    last_report: Instant
+}
+
+impl Progress {
+    /// Start tracking a download of `total` bytes (if known), `done` of which we already had.
+    pub fn new(name: &str, total: Option<u64>, done: u64) -> Self {
+        let overall = OVERALL.lock().ok().and_then(|overall| overall.clone());
+        if let (Some(overall), Some(total)) = (&overall, total) {
+            overall.inc_length(total.saturating_sub(done));
+        }
+
+        let bar = if stdout().is_terminal() {
+            let bar = match total {
+                Some(total) => ProgressBar::new(total).with_style(ProgressStyle::with_template(
+                    "{prefix} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}"
+                ).unwrap().progress_chars("=> ")),
+                None => ProgressBar::no_length().with_style(ProgressStyle::with_template(
+                    "{prefix} {spinner} {bytes} {bytes_per_sec}"
+                ).unwrap())
+            };
+
+            // Keep the overall bar at the bottom
+            let bar = match &overall {
+                Some(overall) => bars().insert_before(overall, bar),
+                None => bars().add(bar)
+            };
+            bar.set_prefix(name.to_string());
+            bar.set_position(done);
+            bar.reset_eta();
+            Some(bar)
+        } else {
+            None
+        };
+        let now = Instant::now();
+        Self {
+            name: name.to_string(),
+            bar,
+            overall,
+            total,
+            done,
+            resumed: done,
+            started: now,
+            last_report: now
+        }
+    }
+
+    /// Record that another chunk has been downloaded.
+    pub fn inc(&mut self, len: u64) {
+        self.done += len;
+        if let Some(overall) = &self.overall {
+            // Downloads of unknown size only add to the total as they go
+            if self.total.is_none() {
+                overall.inc_length(len);
+            }
+            overall.inc(len);
+        }
+        match &self.bar {
+            Some(bar) => bar.set_position(self.done),
+            None => if self.last_report.elapsed() >= REPORT_INTERVAL {
+                self.last_report = Instant::now();
+                note(&self.status());
+            }
+        }
+    }
+
+    /// Stop showing progress once the download is over.
+    pub fn finish(&self) {
+        match &self.bar {
+            Some(bar) => bar.finish(),
+            None => note(&self.status())
+        }
+    }
+
+    /// A plain text line saying which download this is, how far along it is, how fast, and how much
+    /// longer it'll take.
+    fn status(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (self.done - self.resumed) as f64 / elapsed
+        } else {
+            0.0
+        };
+        match self.total {
+            Some(total) if total > 0 => {
+                let eta = if rate > 0.0 {
+                    HumanDuration(Duration::from_secs_f64(
+                        total.saturating_sub(self.done) as f64 / rate
+                    )).to_string()
+                } else {
+                    "unknown".to_string()
+                };
+                format!(
+                    "{}: Downloaded {} / {} ({}%) at {}/s, ETA {}",
+                    self.name, HumanBytes(self.done), HumanBytes(total), self.done * 100 / total,
+                    HumanBytes(rate as u64), eta
+                )
+            }, _ => format!(
+                "{}: Downloaded {} at {}/s",
+                self.name, HumanBytes(self.done), HumanBytes(rate as u64)
+            )
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // A download that failed partway won't be finishing its part of the total
+        if let (Some(overall), Some(total)) = (&self.overall, self.total) {
+            let left = total.saturating_sub(self.done);
+            overall.set_length(overall.length().unwrap_or(0).saturating_sub(left));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+// Author(s): Dylan Turner
+//! Where aipman keeps things, for the user's or the system's packages, maybe under another root
+
+use std::{
+    fs::metadata,
+    os::unix::fs::MetadataExt,
+    path::{
+        Path, PathBuf
+    }, sync::{
+        OnceLock,
+        atomic::{
+            AtomicBool, Ordering
+        }
+    }
+};
+
+/// Default install directory for system packages. Can be changed with `system_install_dir`.
+pub const SYSTEM_APP_DIR: &str = "/opt/appimages";
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/aipman";
+pub const SYSTEM_DATA_DIR: &str = "/var/lib/aipman";
+pub const SYSTEM_CACHE_DIR: &str = "/var/cache/aipman";
+
+/// Where launchers for system packages go.
+pub const SYSTEM_BIN_DIR: &str = "/usr/local/bin";
+
+/// Where desktop entries and icons for system packages go.
+pub const SYSTEM_SHARE_DIR: &str = "/usr/local/share";
+
+static SYSTEM: AtomicBool = AtomicBool::new(false);
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Switch between managing system packages and the user's. Should be done once, before anything
+/// else, since every path aipman uses depends on it.
+pub fn set_system(system: bool) {
+    SYSTEM.store(system, Ordering::SeqCst);
+}
+
+/// Are we managing system packages?
+pub fn is_system() -> bool {
+    SYSTEM.load(Ordering::SeqCst)
+}
+
+/// Put every path aipman uses under another directory, e.g. the filesystem tree of a system image
+/// being built. Should be done once, before anything else. The root should be absolute.
+pub fn set_root(root: PathBuf) {
+    let _ = ROOT.set(root);
+}
+
+/// Is there an alternate root?
+pub fn is_rooted() -> bool {
+    ROOT.get().is_some()
+}
+
+/// Move a path under the alternate root, if there is one.
+pub fn rooted(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match ROOT.get() {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf()
+    }
+}
+
+/// Where a path under the alternate root will be once that root is the real one. Links and desktop
+/// entries need these, since they're used from inside the image.
+pub fn unrooted(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match ROOT.get().and_then(|root| path.strip_prefix(root).ok()) {
+        Some(rest) => Path::new("/").join(rest),
+        None => path.to_path_buf()
+    }
+}
+
+/// Are we running as root? /proc/self belongs to whoever we're running as.
+pub fn is_root() -> bool {
+    metadata("/proc/self").map(|meta| meta.uid() == 0).unwrap_or(false)
+}
@@ -0,0 +1,174 @@
+// Author(s): Dylan Turner
+//! Put launchers for installed AppImages on the PATH
+
+use std::{
+    env::{
+        split_paths, var_os
+    }, path::{
+        Path, PathBuf
+    }, fs::{
+        create_dir_all, read_dir, read_link, remove_file, symlink_metadata
+    }, os::unix::fs::{
+        PermissionsExt, symlink
+    }
+};
+use crate::{
+    config::{
+        expand_home, load_config
+    }, error::{
+        Context, Result
+    }, pkg::{
+        Package, app_dir
+    }, scope::{
+        is_rooted, is_system, rooted, unrooted, SYSTEM_BIN_DIR
+    }
+};
+
+/// Where user launchers go unless the config's `bin_dir` says otherwise.
+pub const DEFAULT_BIN_DIR: &str = "~/.local/bin";
+
+/// Directory launchers go in: the config's `bin_dir` or ~/.local/bin, or /usr/local/bin for system
+/// packages.
+pub fn bin_dir() -> Result<PathBuf> {
+    if is_system() {
+        return Ok(rooted(SYSTEM_BIN_DIR));
+    }
+    let bin_dir = load_config()?.bin_dir.unwrap_or(DEFAULT_BIN_DIR.to_string());
+    Ok(rooted(expand_home(&bin_dir)?))
+}
+
+/// Every command a package gets a launcher for: its name, then any aliases from the config.
+pub fn commands(pkg: &Package) -> Result<Vec<String>> {
+    let mut commands = vec![ pkg.name.clone() ];
+    if let Some(aliases) = load_config()?.aliases.get(&pkg.name) {
+        commands.extend(aliases.iter().cloned());
+    }
+    Ok(commands)
+}
+
+/// Link `<bin dir>/<command>` to a package's AppImage for its name and each of its aliases,
+/// returning the link for its name if one was made.
+///
+/// Anything already at those paths that isn't one of our launchers is left alone.
+pub fn install_shim(pkg: &Package) -> Result<Option<PathBuf>> {
+    let bin_dir = bin_dir()?;
+    create_dir_all(&bin_dir).context("Failed to create launcher path")?;
+    warn_if_off_path(&bin_dir);
+
+    let target = unrooted(pkg.install_path()?);
+    let mut main_shim = None;
+    for command in commands(pkg)? {
+        let shim = bin_dir.join(&command);
+        if symlink_metadata(&shim).is_ok() {
+            if !is_ours(&shim)? {
+                println!(
+                    "Warning: '{}' already exists and isn't from aipman. Not making a launcher.",
+                    shim.display()
+                );
+                continue;
+            }
+            remove_file(&shim).context("Failed to replace launcher")?;
+        }
+        symlink(&target, &shim).context("Failed to create launcher")?;
+        warn_on_collision(&command, &bin_dir);
+        if command == pkg.name {
+            main_shim = Some(shim);
+        }
+    }
+    Ok(main_shim)
+}
+
+/// Remove a package's launchers, if it has them.
+pub fn remove_shim(pkg: &Package) -> Result<()> {
+    for command in commands(pkg)? {
+        remove_command(&command)?;
+    }
+    Ok(())
+}
+
+/// Remove a single launcher, if it's one of ours.
+pub fn remove_command(command: &str) -> Result<()> {
+    let shim = bin_dir()?.join(command);
+    if symlink_metadata(&shim).is_ok() && is_ours(&shim)? {
+        remove_file(&shim).context("Failed to remove launcher")?;
+    }
+    Ok(())
+}
+
+/// Launchers of ours whose AppImage doesn't exist anymore.
+pub fn stale_shims() -> Result<Vec<PathBuf>> {
+    let entries = match read_dir(bin_dir()?) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new())
+    };
+    let mut stale = Vec::new();
+    for shim in entries.flatten().map(|entry| entry.path()) {
+        let target = read_link(&shim).map(rooted);
+        if is_ours(&shim)? && target.is_ok_and(|target| !target.exists()) {
+            stale.push(shim);
+        }
+    }
+    stale.sort();
+    Ok(stale)
+}
+
+/// Is this a link into the install directory?
+fn is_ours(shim: &Path) -> Result<bool> {
+    let app_dir = unrooted(app_dir()?);
+    Ok(read_link(shim).map(|target| target.starts_with(&app_dir)).unwrap_or(false))
+}
+
+/// Find the other programs on PATH with the same name as a launcher.
+///
+/// Returns whether each one comes before the launcher's directory on PATH, along with its path.
+fn find_on_path(command: &str, bin_dir: &Path) -> Vec<(bool, PathBuf)> {
+    let mut found = Vec::new();
+    let mut before = true;
+    for dir in split_paths(&var_os("PATH").unwrap_or_default()) {
+        if dir == bin_dir {
+            before = false;
+            continue;
+        }
+        let program = dir.join(command);
+        let executable = program.metadata()
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if executable {
+            found.push((before, program));
+        }
+    }
+    found
+}
+
+/// Warn if a launcher and another program on PATH have the same name, and say which one wins.
+fn warn_on_collision(command: &str, bin_dir: &Path) {
+    if is_rooted() {
+        return;
+    }
+    for (before, program) in find_on_path(command, bin_dir) {
+        if before {
+            println!(
+                "Warning: '{}' comes first on PATH, so typing '{}' won't run the launcher.",
+                program.display(), command
+            );
+        } else {
+            println!(
+                "Warning: The launcher '{}' hides '{}' since it comes first on PATH.",
+                command, program.display()
+            );
+        }
+    }
+}
+
+/// Warn if launchers in the bin dir can't be run by name.
+fn warn_if_off_path(bin_dir: &Path) {
+    if is_rooted() {
+        return;
+    }
+    if !split_paths(&var_os("PATH").unwrap_or_default()).any(|dir| dir == bin_dir) {
+        println!(
+            "Warning: '{}' isn't on your PATH, so launchers in it can't be run by name.",
+            bin_dir.display()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// Author(s): Dylan Turner
+//! Helpers shared by the tests
+
+use std::{
+    collections::HashMap,
+    env::{
+        set_var, temp_dir
+    },
+    fs::{
+        create_dir_all, remove_dir_all
+    }, io::{
+        BufRead, BufReader, Write
+    }, net::{
+        TcpListener, TcpStream
+    }, path::PathBuf,
+    process,
+    sync::{
+        Arc, Mutex, OnceLock
+    }, thread
+};
+use md4::Md4;
+use sha1::{
+    Digest, Sha1
+};
+use crate::pkg::{
+    to_hex, HOME_VAR
+};
+
+/// An empty directory for a test to work in.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = temp_dir().join(format!("aipman-test-{}-{}", process::id(), name));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Point `$HOME`, the XDG config and data directories, and `$AIPMAN_HOME` into a test directory,
+/// so nothing of the real user's is read or changed. Returns the directory packages go in.
+///
+/// Tests run at the same time, so they all share the one directory and have to use different
+/// package names.
+pub fn app_home() -> PathBuf {
+    static HOME: OnceLock<PathBuf> = OnceLock::new();
+    HOME.get_or_init(|| {
+        let home = test_dir("home");
+        let apps = home.join("Applications");
+        create_dir_all(&apps).unwrap();
+        set_var("HOME", &home);
+        set_var("XDG_CONFIG_HOME", home.join(".config"));
+        set_var("XDG_DATA_HOME", home.join(".local/share"));
+        set_var(HOME_VAR, &apps);
+        apps
+    }).clone()
+}
+
+/// Bytes that don't repeat, so a block of them can only be found where it really is.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+    }).collect()
+}
+
+/// Make a .zsync file for `data` the way zsyncmake does, with full length checksums. The file
+/// name comes from the end of `url`.
+pub fn zsync_file(data: &[u8], block_size: usize, seq_matches: usize, url: &str) -> Vec<u8> {
+    let mut bytes = format!(
+        "zsync: 0.6.2\nFilename: {}\nBlocksize: {}\nLength: {}\nHash-Lengths: {},4,16\nURL: {}\n\
+            SHA-1: {}\n\n",
+        url.rsplit('/').next().unwrap_or(url), block_size, data.len(), seq_matches, url,
+        to_hex(&Sha1::digest(data))
+    ).into_bytes();
+    for block in data.chunks(block_size) {
+        let mut block = block.to_vec();
+        block.resize(block_size, 0);
+        let (mut a, mut b) = (0u16, 0u16);
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u16);
+            b = b.wrapping_add(((block_size - i) as u16).wrapping_mul(*byte as u16));
+        }
+        bytes.extend(a.to_be_bytes());
+        bytes.extend(b.to_be_bytes());
+        bytes.extend(Md4::digest(&block));
+    }
+    bytes
+}
+
+/// The smallest file that passes for a type 2 AppImage: a 64-bit ELF header with the AppImage
+/// magic and a section table holding just the string table and `.upd_info`. It has no machine
+/// type, so it isn't refused as being for another architecture.
+pub fn fake_appimage(update_info: &str) -> Vec<u8> {
+    let names = b"\0.shstrtab\0.upd_info\0";
+    let mut file = vec![0; 832];
+    file[..4].copy_from_slice(b"\x7fELF");
+    file[4..7].copy_from_slice(&[ 2, 1, 1 ]);
+    file[8..11].copy_from_slice(b"AI\x02");
+    file[16..18].copy_from_slice(&2u16.to_le_bytes());
+    file[40..48].copy_from_slice(&640u64.to_le_bytes());
+    file[58..64].copy_from_slice(&[ 64, 0, 3, 0, 1, 0 ]);
+    file[64..64 + names.len()].copy_from_slice(names);
+    file[128..128 + update_info.len()].copy_from_slice(update_info.as_bytes());
+
+    // Section 0 is always empty
+    for (index, (name, offset, size)) in [ (1u32, 64u64, names.len()), (11, 128, 512) ]
+            .into_iter().enumerate() {
+        let entry = 640 + 64 * (index + 1);
+        file[entry..entry + 4].copy_from_slice(&name.to_le_bytes());
+        file[entry + 24..entry + 32].copy_from_slice(&offset.to_le_bytes());
+        file[entry + 32..entry + 40].copy_from_slice(&(size as u64).to_le_bytes());
+    }
+    file
+}
+
+/// A tiny HTTP server on a free port that runs until the tests end. Each file is at `/<path>`,
+/// and anything else is a 404.
+pub struct Server {
+    pub url: String,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>
+}
+
+impl Server {
+    /// Start a server. Range requests get a 206 if `ranges` is set. Otherwise they get the whole
+    /// file, like a server that doesn't support them.
+    pub fn new(ranges: bool) -> Self {
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = files.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                respond(stream, &served, ranges);
+            }
+        });
+        Self { url, files }
+    }
+
+    /// Serve `contents` at `/<path>`, replacing what was there.
+    pub fn add(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.files.lock().unwrap().insert(format!("/{}", path), contents.into());
+    }
+}
+
+/// Answer one request for a [`Server`].
+fn respond(mut stream: TcpStream, files: &Mutex<HashMap<String, Vec<u8>>>, ranges: bool) {
+    let mut path = String::new();
+    let mut range: Option<(usize, usize)> = None;
+    for line in BufReader::new(&stream).lines() {
+        let line = line.unwrap_or_default();
+        if line.is_empty() {
+            break;
+        } else if let Some(request) = line.strip_prefix("GET ") {
+            path = request.split(' ').next().unwrap_or_default().to_string();
+        } else if let Some((key, value)) = line.split_once(':') {
+            if key.eq_ignore_ascii_case("range") {
+                range = value.trim().strip_prefix("bytes=").and_then(|range| range.split_once('-'))
+                    .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)));
+            }
+        }
+    }
+
+    let files = files.lock().unwrap();
+    let (status, body) = match (files.get(&path), range) {
+        (Some(contents), Some((first, last))) if ranges =>
+            ("206 Partial Content", &contents[first..=last.min(contents.len() - 1)]),
+        (Some(contents), _) => ("200 OK", &contents[..]),
+        (None, _) => ("404 Not Found", &[][..])
+    };
+    let _ = write!(
+        stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len()
+    );
+    let _ = stream.write_all(body);
+}
@@ -0,0 +1,224 @@
+// Author(s): Dylan Turner
+//! Find the newest release of an AppImage from the update information embedded in it
+
+use glob::Pattern;
+use reqwest::{
+    blocking::Client,
+    header::{
+        ACCEPT, USER_AGENT
+    }
+};
+use serde::Deserialize;
+use serde_json::from_slice;
+use crate::{
+    config::load_config,
+    error::{
+        Context, Error, Result
+    }
+};
+
+/// Where the GitHub API is unless the config's `github_api` says otherwise.
+pub const DEFAULT_GITHUB_API: &str = "https://api.github.com";
+
+/// The newest release an AppImage's update information points to.
+#[derive(Clone, Debug)]
+pub struct Release {
+    /// Version of the release, if the update information says. Plain zsync urls don't.
+    pub version: Option<String>,
+    pub zsync_url: String
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    assets: Vec<GithubAsset>
+}
+
+#[derive(Deserialize)]
+struct GithubAsset {
+    name: String,
+    browser_download_url: String
+}
+
+/// Can aipman update from this update information? Only the zsync transports are supported.
+pub fn is_supported(update_info: &str) -> bool {
+    let fields: Vec<&str> = update_info.trim().split('|').collect();
+    matches!(fields[..], [ "zsync", _ ] | [ "gh-releases-zsync", _, _, _, _ ])
+}
+
+/// Look up the newest release. None if the update information uses a transport we don't support.
+///
+/// - `zsync|<url>` points straight at the .zsync file of the newest release.
+/// - `gh-releases-zsync|<owner>|<repo>|<tag>|<file pattern>` finds the .zsync file among the
+///   assets of a GitHub release. The tag can be `latest`, `latest-pre` (which includes
+///   prereleases), or the name of a tag.
+pub fn latest_release(update_info: &str) -> Result<Option<Release>> {
+    let fields: Vec<&str> = update_info.trim().split('|').collect();
+    match fields[..] {
+        [ "zsync", url ] if !url.is_empty() => Ok(Some(Release {
+            version: None,
+            zsync_url: url.to_string()
+        })), [ "gh-releases-zsync", owner, repo, tag, pattern ] => {
+            let api = load_config()?.github_api.unwrap_or(DEFAULT_GITHUB_API.to_string());
+            github_release(&api, owner, repo, tag, pattern).map(Some)
+        }, _ => Ok(None)
+    }
+}
+
+/// Find a GitHub release and the asset in it matching `pattern`, using the API at `api`.
+fn github_release(
+        api: &str, owner: &str, repo: &str, tag: &str, pattern: &str) -> Result<Release> {
+    let api = api.trim_end_matches('/');
+    let url = match tag {
+        "latest" => format!("{}/repos/{}/{}/releases/latest", api, owner, repo),
+        "latest-pre" | "latest-all" => format!("{}/repos/{}/{}/releases", api, owner, repo),
+        tag => format!("{}/repos/{}/{}/releases/tags/{}", api, owner, repo, tag)
+    };
+
+    // GitHub refuses requests without a user agent
+    let bytes = Client::new().get(&url)
+        .header(USER_AGENT, "aipman")
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .context(&format!("Failed to look up release of '{}/{}'", owner, repo))?;
+    let release = if tag.starts_with("latest-") {
+        // Newest first
+        from_slice::<Vec<GithubRelease>>(&bytes).context("Failed to parse release list")?
+            .into_iter().next()
+            .ok_or(Error::NotFound(format!("'{}/{}' has no releases", owner, repo)))?
+    } else {
+        from_slice::<GithubRelease>(&bytes).context("Failed to parse release")?
+    };
+
+    let pattern = Pattern::new(pattern)
+        .map_err(|err| Error::Parse(format!("Bad file pattern '{}': {}", pattern, err)))?;
+    let asset = release.assets.iter().find(|asset| pattern.matches(&asset.name))
+        .ok_or(Error::NotFound(format!(
+            "Release {} of '{}/{}' has no file matching '{}'", release.tag_name, owner, repo,
+            pattern
+        )))?;
+    Ok(Release {
+        version: Some(release.tag_name.trim_start_matches('v').to_string()),
+        zsync_url: asset.browser_download_url.clone()
+    })
+}
+
+/// Guess the version from an AppImage's file name, e.g. `1.2.3` from `App-1.2.3-x86_64.AppImage`.
+pub fn version_from_file_name(file_name: &str) -> Option<String> {
+    let stem = file_name.trim_end_matches(".zsync").trim_end_matches(".AppImage");
+    stem.split(['-', '_']).map(|part| part.trim_start_matches('v'))
+        .find(|part| {
+            part.starts_with(|c: char| c.is_ascii_digit()) && part.contains('.')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '+')
+        }).map(|part| part.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Server;
+    use super::*;
+
+    const PATTERN: &str = "App-*-x86_64.AppImage.zsync";
+
+    /// A release as the GitHub API describes it.
+    fn release(tag: &str) -> String {
+        let asset = |name: &str, file: &str| format!(
+            r#"{{"name":"{}","browser_download_url":"https://dl.test/{}/{}"}}"#, name, tag, file
+        );
+        format!(
+            r#"{{"tag_name":"{}","assets":[{},{},{}]}}"#, tag,
+            asset(&format!("App-{}-aarch64.AppImage.zsync", tag), "aarch64.zsync"),
+            asset(&format!("App-{}-x86_64.AppImage", tag), "x86_64"),
+            asset(&format!("App-{}-x86_64.AppImage.zsync", tag), "x86_64.zsync")
+        )
+    }
+
+    /// A fake GitHub API with a few releases of `o/r`, the newest of them a prerelease.
+    fn github() -> Server {
+        let server = Server::new(false);
+        server.add("repos/o/r/releases/latest", release("v1.9"));
+        server.add("repos/o/r/releases", format!("[{},{}]", release("v2.0-rc1"), release("v1.9")));
+        server.add("repos/o/r/releases/tags/v1.5", release("v1.5"));
+        server.add("repos/o/empty/releases", "[]");
+        server
+    }
+
+    #[test]
+    fn finds_latest_release() {
+        let server = github();
+        let found = github_release(&server.url, "o", "r", "latest", PATTERN).unwrap();
+        assert_eq!(found.version.as_deref(), Some("1.9"));
+        assert_eq!(found.zsync_url, "https://dl.test/v1.9/x86_64.zsync");
+
+        // A trailing slash on the configured API is fine
+        let api = format!("{}/", server.url);
+        assert!(github_release(&api, "o", "r", "latest", PATTERN).is_ok());
+    }
+
+    #[test]
+    fn finds_latest_prerelease() {
+        let server = github();
+        for tag in [ "latest-pre", "latest-all" ] {
+            let found = github_release(&server.url, "o", "r", tag, PATTERN).unwrap();
+            assert_eq!(found.version.as_deref(), Some("2.0-rc1"));
+            assert_eq!(found.zsync_url, "https://dl.test/v2.0-rc1/x86_64.zsync");
+        }
+        let result = github_release(&server.url, "o", "empty", "latest-pre", PATTERN);
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn finds_named_tag() {
+        let server = github();
+        let found = github_release(&server.url, "o", "r", "v1.5", PATTERN).unwrap();
+        assert_eq!(found.version.as_deref(), Some("1.5"));
+        assert_eq!(found.zsync_url, "https://dl.test/v1.5/x86_64.zsync");
+        assert!(github_release(&server.url, "o", "r", "v1.6", PATTERN).is_err());
+    }
+
+    #[test]
+    fn matches_asset_pattern() {
+        let server = github();
+        let find = |pattern| github_release(&server.url, "o", "r", "latest", pattern);
+        assert_eq!(
+            find("App-*-aarch64.AppImage.zsync").unwrap().zsync_url,
+            "https://dl.test/v1.9/aarch64.zsync"
+        );
+
+        // The first match wins, and patterns have to match the whole name
+        assert_eq!(find("*.zsync").unwrap().zsync_url, "https://dl.test/v1.9/aarch64.zsync");
+        assert!(matches!(find("App-*-x86_64"), Err(Error::NotFound(_))));
+        assert!(matches!(find("Other-*.zsync"), Err(Error::NotFound(_))));
+        assert!(matches!(find("App-[.zsync"), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn reads_plain_zsync_info() {
+        let found = latest_release("zsync|https://dl.test/App.AppImage.zsync\n").unwrap().unwrap();
+        assert_eq!(found.version, None);
+        assert_eq!(found.zsync_url, "https://dl.test/App.AppImage.zsync");
+
+        assert!(latest_release("zsync|").unwrap().is_none());
+        let bintray = "bintray-zsync|o|r|App|App-_latestVersion.zsync";
+        assert!(latest_release(bintray).unwrap().is_none());
+        assert!(!is_supported(bintray));
+        assert!(is_supported("gh-releases-zsync|o|r|latest|App-*.zsync"));
+    }
+
+    #[test]
+    fn guesses_versions_from_file_names() {
+        for (file_name, version) in [
+            ("App-1.2.3-x86_64.AppImage", Some("1.2.3")),
+            ("App_v2.0.1_x86_64.AppImage.zsync", Some("2.0.1")),
+            ("App-1.0+git5-x86_64.AppImage", Some("1.0+git5")),
+            ("App-2023.10.AppImage", Some("2023.10")),
+            ("App-x86_64.AppImage", None),
+            ("App-2-x86_64.AppImage", None),
+            ("App-1.2~beta-x86_64.AppImage", None)
+        ] {
+            assert_eq!(version_from_file_name(file_name).as_deref(), version, "{}", file_name);
+        }
+    }
+}
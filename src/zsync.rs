@@ -0,0 +1,542 @@
+// Author(s): Dylan Turner
+//! Build a new version of an AppImage out of the old one, downloading only the blocks that changed
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{
+        Read, Seek, SeekFrom, Write
+    }
+};
+use md4::Md4;
+use reqwest::{
+    StatusCode, Url,
+    blocking::Client,
+    header::RANGE
+};
+use sha1::Sha1;
+use sha2::{
+    Digest, Sha256
+};
+use crate::{
+    error::{
+        Context, Error, Result
+    }, pkg::to_hex,
+    progress::{
+        Progress, note
+    }
+};
+
+/// How much of the old file to read at a time while looking for blocks in it.
+const READ_SIZE: usize = 1 << 20;
+
+/// Everything a .zsync file says about the file it describes.
+#[derive(Clone, Debug)]
+pub struct Control {
+    /// Where to get the file, resolved against the .zsync file's url.
+    pub url: String,
+
+    /// Name the file was made from, which usually has its version in it.
+    pub file_name: Option<String>,
+    pub length: u64,
+    pub sha1: String,
+    block_size: usize,
+
+    /// How many blocks in a row have to match before any of them count.
+    seq_matches: usize,
+
+    /// How many bytes of each block's rolling checksum and MD4 are stored.
+    rsum_bytes: usize,
+    checksum_bytes: usize,
+
+    /// Each block's rolling checksum (cut down to `rsum_bytes`) and MD4 (cut down to
+    /// `checksum_bytes`).
+    blocks: Vec<(u32, Vec<u8>)>
+}
+
+impl Control {
+    /// Download and parse a .zsync file.
+    pub fn fetch(url: &str) -> Result<Self> {
+        let bytes = Client::new().get(url).send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.bytes())
+            .context("Failed to download zsync file")?;
+        Self::parse(&bytes, url)
+    }
+
+    /// Parse a .zsync file: `Key: value` lines, a blank line, then the checksums of every block.
+    pub fn parse(bytes: &[u8], url: &str) -> Result<Self> {
+        let bad = |msg: &str| Error::Parse(format!("Invalid zsync file '{}': {}", url, msg));
+        let header_len = bytes.windows(2).position(|pair| pair == b"\n\n")
+            .ok_or(bad("No end to the header"))?;
+        let header = String::from_utf8_lossy(&bytes[..header_len]);
+        let mut fields = HashMap::new();
+        for line in header.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                // Only the first url is used, in case there are mirrors
+                fields.entry(key.trim().to_string()).or_insert(value.trim().to_string());
+            }
+        }
+        let number = |key: &str| fields.get(key).and_then(|value| value.parse::<u64>().ok())
+            .ok_or(bad(&format!("Missing or bad '{}'", key)));
+
+        let target = match fields.get("URL") {
+            Some(target) => target,
+            None if fields.contains_key("Z-URL") =>
+                return Err(bad("Compressed targets aren't supported")),
+            None => return Err(bad("Missing 'URL'"))
+        };
+        let url = Url::parse(url).and_then(|base| base.join(target))
+            .map_err(|err| bad(&format!("Bad url '{}': {}", target, err)))?.to_string();
+        let file_name = fields.get("Filename").cloned();
+        let length = number("Length")?;
+        let block_size = number("Blocksize")? as usize;
+        let sha1 = fields.get("SHA-1").ok_or(bad("Missing 'SHA-1'"))?.to_lowercase();
+        if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(bad("Bad 'SHA-1'"));
+        }
+        let lengths: Vec<usize> = fields.get("Hash-Lengths").map(|value| value.as_str())
+            .unwrap_or("1,4,16").split(',').filter_map(|len| len.trim().parse().ok()).collect();
+        let (seq_matches, rsum_bytes, checksum_bytes) = match lengths[..] {
+            [ seq, rsum, checksum ]
+                if (1..=2).contains(&seq) && (1..=4).contains(&rsum)
+                    && (3..=16).contains(&checksum) => (seq, rsum, checksum),
+            _ => return Err(bad("Bad 'Hash-Lengths'"))
+        };
+        if block_size == 0 || block_size & (block_size - 1) != 0 {
+            return Err(bad("Block size isn't a power of two"));
+        }
+
+        // Length comes from the file, so it can be anything
+        let num_blocks = usize::try_from(length.div_ceil(block_size as u64)).ok();
+        let sums = &bytes[header_len + 2..];
+        let entry_len = rsum_bytes + checksum_bytes;
+        if num_blocks.and_then(|num_blocks| num_blocks.checked_mul(entry_len)) != Some(sums.len()) {
+            return Err(bad("'Length' doesn't match the number of block checksums"));
+        }
+        let blocks = sums.chunks(entry_len).map(|entry| {
+            let rsum = entry[..rsum_bytes].iter().fold(0, |rsum, byte| (rsum << 8) | *byte as u32);
+            (rsum, entry[rsum_bytes..].to_vec())
+        }).collect();
+
+        Ok(Self {
+            url, file_name, length, sha1, block_size, seq_matches, rsum_bytes, checksum_bytes,
+            blocks
+        })
+    }
+
+    /// Only the low `rsum_bytes` of a rolling checksum are stored, so only those are compared.
+    fn rsum_mask(&self) -> u32 {
+        (u64::from(u32::MAX) >> (8 * (4 - self.rsum_bytes))) as u32
+    }
+
+    /// Length of a block, which is shorter for the last one.
+    fn block_len(&self, block: usize) -> usize {
+        (self.length - (block * self.block_size) as u64).min(self.block_size as u64) as usize
+    }
+
+    /// Find the blocks of the new file that are somewhere in the old one, by sliding a rolling
+    /// checksum along it. Returns where in the old file each block was found.
+    ///
+    /// Only a window of the old file is kept in memory, since AppImages can be huge.
+    fn find_blocks(&self, old: impl Read) -> Result<Vec<Option<u64>>> {
+        let block_size = self.block_size;
+        let mask = self.rsum_mask();
+        let mut by_rsum: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (block, (rsum, _)) in self.blocks.iter().enumerate() {
+            by_rsum.entry(*rsum).or_default().push(block);
+        }
+
+        // The new file's last block was padded with zeros, so the old one gets the same
+        let mut window = Window::new(old, 2 * block_size);
+
+        // Keep a second checksum going for the block after this one, for blocks that need a run
+        let mut found = vec![None; self.blocks.len()];
+        let mut pos = 0;
+        let data = window.slice(0, 2 * block_size)?;
+        let mut sums = (Rsum::new(&data[..block_size]), Rsum::new(&data[block_size..]));
+        loop {
+            let data = window.slice(pos, pos + 2 * block_size + 1)?;
+            if data.len() < 2 * block_size {
+                break;
+            }
+            let mut matched = false;
+            if let Some(candidates) = by_rsum.get(&(sums.0.value() & mask)) {
+                let mut digest = None;
+                for &block in candidates {
+                    let in_run = self.seq_matches < 2 || block + 1 == self.blocks.len()
+                        || self.blocks[block + 1].0 == sums.1.value() & mask;
+                    if !in_run {
+                        continue;
+                    }
+                    let digest = digest.get_or_insert_with(|| Md4::digest(&data[..block_size]));
+                    if digest[..self.checksum_bytes] == self.blocks[block].1[..] {
+                        found[block] = found[block].or(Some(pos as u64));
+                        matched = true;
+                    }
+                }
+            }
+
+            // Skip over a block once it's found, since blocks rarely overlap
+            if matched {
+                pos += block_size;
+                let data = window.slice(pos, pos + 2 * block_size)?;
+                if data.len() == 2 * block_size {
+                    sums = (sums.1, Rsum::new(&data[block_size..]));
+                }
+            } else if data.len() > 2 * block_size {
+                sums.0.roll(data[0], data[block_size], block_size);
+                sums.1.roll(data[block_size], data[2 * block_size], block_size);
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// A window onto a file that's read front to back, so only part of it is in memory at once. The
+/// file looks like it has `padding` zeros on the end.
+struct Window<R> {
+    reader: R,
+    buf: Vec<u8>,
+
+    /// Where in the file `buf` starts.
+    start: usize,
+    padding: usize,
+    done: bool
+}
+
+impl<R: Read> Window<R> {
+    fn new(reader: R, padding: usize) -> Self {
+        Self { reader, buf: Vec::new(), start: 0, padding, done: false }
+    }
+
+    /// Get bytes `from..to` of the file, or fewer if it ends first. Everything before `from` may
+    /// be forgotten, so `from` can't go backwards.
+    fn slice(&mut self, from: usize, to: usize) -> Result<&[u8]> {
+        // Only shift the buffer once in a while, since it means moving everything in it
+        if from - self.start >= READ_SIZE {
+            let drop = (from - self.start).min(self.buf.len());
+            self.buf.drain(..drop);
+            self.start += drop;
+        }
+        while self.start + self.buf.len() < to && !self.done {
+            let len = self.buf.len();
+            self.buf.resize(len + READ_SIZE, 0);
+            let read = self.reader.read(&mut self.buf[len..])
+                .context("Failed to read old version")?;
+            self.buf.truncate(len + read);
+            if read == 0 {
+                self.buf.resize(len + self.padding, 0);
+                self.done = true;
+            }
+        }
+        let end = to.saturating_sub(self.start).min(self.buf.len());
+        Ok(&self.buf[(from - self.start).min(end)..end])
+    }
+}
+
+/// The rsync style rolling checksum zsync uses.
+#[derive(Clone, Copy)]
+struct Rsum {
+    a: u16,
+    b: u16
+}
+
+impl Rsum {
+    fn new(block: &[u8]) -> Self {
+        let mut sum = Self { a: 0, b: 0 };
+        for (i, byte) in block.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(*byte as u16);
+            sum.b = sum.b.wrapping_add(((block.len() - i) as u16).wrapping_mul(*byte as u16));
+        }
+        sum
+    }
+
+    /// Slide the window one byte along, dropping `old` and adding `new`.
+    fn roll(&mut self, old: u8, new: u8, block_size: usize) {
+        self.a = self.a.wrapping_sub(old as u16).wrapping_add(new as u16);
+        self.b = self.b.wrapping_sub((block_size as u16).wrapping_mul(old as u16))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        ((self.a as u32) << 16) | self.b as u32
+    }
+}
+
+/// Build the file a .zsync file describes at `out_path`, copying every block it can from
+/// `old_file` and downloading the rest. Returns the SHA-256 of the new file.
+///
+/// The result is checked against the .zsync file's SHA-1, so a failure never leaves a bad file
+/// looking finished.
+pub fn build(control: &Control, old_file: &str, out_path: &str, name: &str) -> Result<Sha256> {
+    let mut old = File::open(old_file).context("Failed to read old version")?;
+    let found = control.find_blocks(&mut old)?;
+    let reused = found.iter().flatten().count();
+    note(&format!(
+        "Reusing {} of {} blocks of '{}' from the installed version...",
+        reused, found.len(), name
+    ));
+
+    let mut out = File::create(out_path).context("Failed to save file")?;
+    out.set_len(control.length).context("Failed to save file")?;
+    let mut missing = Vec::new();
+    let mut buf = Vec::with_capacity(control.block_size);
+    for (block, pos) in found.iter().enumerate() {
+        let offset = (block * control.block_size) as u64;
+        match pos {
+            Some(pos) => {
+                // A block found at the very end may run into the padding, which is zeros
+                let len = control.block_len(block);
+                buf.clear();
+                old.seek(SeekFrom::Start(*pos)).context("Failed to read old version")?;
+                (&mut old).take(len as u64).read_to_end(&mut buf)
+                    .context("Failed to read old version")?;
+                buf.resize(len, 0);
+                out.seek(SeekFrom::Start(offset)).context("Failed to save file")?;
+                out.write_all(&buf).context("Failed to save file")?;
+            }, None => match missing.last_mut() {
+                // Runs of missing blocks are fetched with one request
+                Some((_, end)) if *end == block => *end = block + 1,
+                _ => missing.push((block, block + 1))
+            }
+        }
+    }
+    drop(old);
+
+    let total = missing.iter().flat_map(|(start, end)| *start..*end)
+        .map(|block| control.block_len(block) as u64).sum();
+    let mut progress = Progress::new(name, Some(total), 0);
+    let client = Client::new();
+    for (start, end) in missing {
+        let first = (start * control.block_size) as u64;
+        let last = ((end * control.block_size) as u64).min(control.length) - 1;
+        let mut response = client.get(&control.url)
+            .header(RANGE, format!("bytes={}-{}", first, last)).send()
+            .and_then(|response| response.error_for_status())
+            .context("Failed to download changed blocks")?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::Network(format!(
+                "Server for '{}' doesn't support range requests", control.url
+            )));
+        }
+
+        out.seek(SeekFrom::Start(first)).context("Failed to save file")?;
+        let mut buf = [0; 8192];
+        let mut left = last + 1 - first;
+        while left > 0 {
+            let len = response.read(&mut buf).map_err(|err| Error::Network(
+                format!("Failed to download changed blocks: {}", err)
+            ))?;
+            if len == 0 {
+                return Err(Error::Network("Download of changed blocks ended early".to_string()));
+            }
+            let len = len.min(left as usize);
+            out.write_all(&buf[..len]).context("Failed to save file")?;
+            progress.inc(len as u64);
+            left -= len as u64;
+        }
+    }
+    progress.finish();
+    drop(out);
+
+    // Make sure it all went together right, hashing for the caller at the same time
+    let (actual, sha256) = hash_file(out_path)?;
+    if actual != control.sha1 {
+        return Err(Error::Integrity(format!(
+            "SHA-1 mismatch for '{}'! Expected {}, but got {}", out_path, control.sha1, actual
+        )));
+    }
+    Ok(sha256)
+}
+
+/// Get the hex SHA-1 of a file, which is what .zsync files identify files by.
+pub fn file_sha1(file_name: &str) -> Result<String> {
+    hash_file(file_name).map(|(sha1, _)| sha1)
+}
+
+/// Hash a file with SHA-1 and SHA-256 in one pass.
+fn hash_file(file_name: &str) -> Result<(String, Sha256)> {
+    let mut file = File::open(file_name).context("Failed to open file to hash")?;
+    let mut sha1 = Sha1::new();
+    let mut sha256 = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        let len = file.read(&mut buf).context("Failed to read file to hash")?;
+        if len == 0 {
+            break;
+        }
+        sha1.update(&buf[..len]);
+        sha256.update(&buf[..len]);
+    }
+    Ok((to_hex(&sha1.finalize()), sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{
+            read, write
+        }, io::Cursor
+    };
+    use crate::testing::{
+        Server, noise, test_dir, zsync_file
+    };
+    use super::*;
+
+    fn make_control(data: &[u8], block_size: usize, seq_matches: usize, url: &str) -> Control {
+        Control::parse(&zsync_file(data, block_size, seq_matches, url), url).unwrap()
+    }
+
+    /// Where each block of `new` should be found in it with `inserted` bytes put in at `at`.
+    fn shifted(control: &Control, at: usize, inserted: usize) -> Vec<Option<u64>> {
+        (0..control.blocks.len()).map(|block| {
+            let start = block * control.block_size;
+            if start + control.block_len(block) <= at {
+                Some(start as u64)
+            } else if start >= at {
+                Some((start + inserted) as u64)
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn rsum_rolls_like_a_fresh_sum() {
+        let data = noise(5000, 1);
+        let block_size = 1024;
+        let mut sum = Rsum::new(&data[..block_size]);
+        for pos in 0..data.len() - block_size {
+            assert_eq!(sum.value(), Rsum::new(&data[pos..pos + block_size]).value(), "at {}", pos);
+            sum.roll(data[pos], data[pos + block_size], block_size);
+        }
+    }
+
+    #[test]
+    fn finds_blocks_that_moved() {
+        let new = noise(10 * 1024 + 100, 2);
+        let control = make_control(&new, 1024, 1, "http://127.0.0.1/new");
+        let mut old = new[..3000].to_vec();
+        old.extend(noise(700, 3));
+        old.extend(&new[3000..]);
+
+        let found = control.find_blocks(Cursor::new(&old)).unwrap();
+        assert_eq!(found, shifted(&control, 3000, 700));
+    }
+
+    #[test]
+    fn finds_blocks_across_reads() {
+        let new = noise(3 * READ_SIZE / 2, 4);
+        let control = make_control(&new, 2048, 1, "http://127.0.0.1/new");
+        let at = READ_SIZE - 1024;
+        let mut old = new[..at].to_vec();
+        old.extend(noise(3000, 5));
+        old.extend(&new[at..]);
+
+        let found = control.find_blocks(Cursor::new(&old)).unwrap();
+        assert_eq!(found, shifted(&control, at, 3000));
+    }
+
+    #[test]
+    fn needs_runs_when_asked() {
+        // With two matches needed, a block followed by a changed one can't be used
+        let new = noise(8 * 1024, 6);
+        let control = make_control(&new, 1024, 2, "http://127.0.0.1/new");
+        let mut old = new.clone();
+        old[4 * 1024 + 10] ^= 0xff;
+
+        let found = control.find_blocks(Cursor::new(&old)).unwrap();
+        let expected: Vec<Option<u64>> = (0..8)
+            .map(|block| (block != 3 && block != 4).then_some(block * 1024)).collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn parses_zsyncmake_output() {
+        let control = Control::parse(
+            include_bytes!("../tests/data/sample.txt.zsync"),
+            "http://example.com/files/sample.txt.zsync"
+        ).unwrap();
+        assert_eq!(control.url, "http://example.com/files/sample.txt");
+        assert_eq!(control.file_name.as_deref(), Some("sample.txt"));
+        assert_eq!(control.length, 9000);
+        assert_eq!(control.sha1, "046a51c190d53690ba24e2724ffcaf3bca8be7f7");
+        assert_eq!(
+            (control.block_size, control.seq_matches, control.rsum_bytes, control.checksum_bytes),
+            (2048, 2, 2, 3)
+        );
+        assert_eq!(control.blocks.len(), 5);
+
+        let found = control.find_blocks(Cursor::new(include_bytes!("../tests/data/sample.txt")))
+            .unwrap();
+        assert_eq!(found, vec![ Some(0), Some(2048), Some(4096), Some(6144), Some(8192) ]);
+    }
+
+    #[test]
+    fn rejects_bad_zsync_files() {
+        let url = "http://127.0.0.1/new.zsync";
+        let sha1 = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+        let header = format!("zsync: 0.6.2\nBlocksize: 2048\nLength: 10\nSHA-1: {}\n", sha1);
+        let sums = "x".repeat(20);
+        assert!(Control::parse(format!("{}URL: new\n\n{}", header, sums).as_bytes(), url).is_ok());
+        for bytes in [
+            format!("{}URL: new", header),
+            format!("{}Z-URL: new.gz\n\n", header),
+            format!("{}URL: new\n\n{}", header.replace("2048", "2000"), sums),
+            format!("{}URL: new\nHash-Lengths: 2,2,1\n\n{}", header, sums),
+            format!("{}URL: new\n\n", header),
+            format!("{}URL: new\n\n{}", header, "x".repeat(40)),
+            format!(
+                "{}URL: new\n\n{}",
+                header.replace("2048", "1").replace("Length: 10", "Length: 18446744073709551615"),
+                sums
+            ),
+            format!("{}URL: new\n\n{}", header.replace(sha1, "00"), sums),
+            format!("{}URL: new\n\n{}", header.replace(sha1, &"g".repeat(40)), sums)
+        ] {
+            let result = Control::parse(bytes.as_bytes(), url);
+            assert!(matches!(result, Err(Error::Parse(_))), "{}", bytes);
+        }
+    }
+
+    #[test]
+    fn builds_from_changed_blocks() {
+        let dir = test_dir("zsync-build");
+        let new = noise(20 * 1024 + 10, 7);
+        let mut old = new.clone();
+        old[5 * 1024] ^= 0xff;
+        old.truncate(15 * 1024);
+        let old_path = dir.join("old");
+        let out_path = dir.join("out");
+        write(&old_path, &old).unwrap();
+
+        let server = Server::new(true);
+        server.add("new", new.clone());
+        let control = make_control(&new, 1024, 1, &format!("{}/new", server.url));
+        let sha256 = build(
+            &control, old_path.to_str().unwrap(), out_path.to_str().unwrap(), "test"
+        ).unwrap();
+        assert!(read(&out_path).unwrap() == new);
+        assert_eq!(sha256.finalize()[..], Sha256::digest(&new)[..]);
+    }
+
+    #[test]
+    fn fails_without_range_support() {
+        let dir = test_dir("zsync-no-ranges");
+        let new = noise(4 * 1024, 8);
+        let old_path = dir.join("old");
+        write(&old_path, &new[..2048]).unwrap();
+
+        let server = Server::new(false);
+        server.add("new", new.clone());
+        let control = make_control(&new, 1024, 1, &format!("{}/new", server.url));
+        let result = build(
+            &control, old_path.to_str().unwrap(), dir.join("out").to_str().unwrap(), "test"
+        );
+        assert!(matches!(result, Err(Error::Network(_))), "{:?}", result.err());
+    }
+}